@@ -0,0 +1,38 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use ricecomp::{read::RCDecoder, write::RCEncoder};
+
+const NX: usize = 1 << 20;
+const NBLOCK: usize = 32;
+
+/// Noisy image rows: a smooth gradient plus a few bits of pseudo-random noise
+fn image() -> Vec<i32> {
+    let mut seed: u32 = 12345;
+    (0..NX as i32)
+        .map(|x| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            1000 + (x % 4096) / 8 + ((seed >> 16) & 0x3f) as i32
+        })
+        .collect()
+}
+
+fn decode(c: &mut Criterion) {
+    let input = image();
+    let mut encoded = Vec::new();
+    RCEncoder::new(&mut encoded)
+        .encode(&input, NX, NBLOCK)
+        .unwrap();
+
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Bytes((NX * 4) as u64));
+
+    group.bench_function("slice", |b| {
+        let decoder = RCDecoder::new();
+        let mut out = vec![0i32; NX];
+        b.iter(|| decoder.decode(&encoded, NX, NBLOCK, &mut out).unwrap())
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+use std::{fs::File, io::Seek};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use ricecomp::write::{encode_into, max_compressed_size, RCEncoder};
+
+const NX: usize = 1 << 20;
+const NBLOCK: usize = 32;
+
+/// Noisy image rows: a smooth gradient plus a few bits of pseudo-random noise
+fn image() -> Vec<i32> {
+    let mut seed: u32 = 12345;
+    (0..NX as i32)
+        .map(|x| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            1000 + (x % 4096) / 8 + ((seed >> 16) & 0x3f) as i32
+        })
+        .collect()
+}
+
+fn encode(c: &mut Criterion) {
+    let input = image();
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Bytes((NX * 4) as u64));
+
+    group.bench_function("vec", |b| {
+        b.iter_batched_ref(
+            || Vec::with_capacity(NX * 4),
+            |out| RCEncoder::new(out).encode(&input, NX, NBLOCK).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.bench_function("slice", |b| {
+        let mut out =
+            vec![0u8; max_compressed_size(NX, NBLOCK, ricecomp::sample::SampleWidth::Int)];
+        b.iter(|| encode_into(&input, NX, NBLOCK, &mut out).unwrap())
+    });
+
+    group.bench_function("file", |b| {
+        let path = std::env::temp_dir().join(format!("ricecomp-bench-{}", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        b.iter(|| {
+            file.rewind().unwrap();
+            RCEncoder::new(&mut file)
+                .encode(&input, NX, NBLOCK)
+                .unwrap()
+        });
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
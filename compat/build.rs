@@ -0,0 +1,8 @@
+fn main() {
+    println!("cargo:rerun-if-changed=../c_example/ricecomp.c");
+
+    cc::Build::new()
+        .file("../c_example/ricecomp.c")
+        .warnings(false)
+        .compile("cfitsio_ricecomp");
+}
@@ -0,0 +1,171 @@
+//! Safe wrappers around the CFITSIO Rice coder in `c_example/ricecomp.c`, so
+//! the tests can compare it byte for byte with the Rust port.
+
+#![deny(clippy::undocumented_unsafe_blocks)]
+
+use std::ffi::{c_int, c_schar, c_short, c_uchar, c_uint, c_ushort};
+
+use ricecomp::{read::RCDecoder, sample::SampleWidth};
+
+extern "C" {
+    fn fits_rcomp(a: *const c_int, nx: c_int, c: *mut c_uchar, clen: c_int, nblock: c_int)
+        -> c_int;
+    fn fits_rcomp_short(
+        a: *const c_short,
+        nx: c_int,
+        c: *mut c_uchar,
+        clen: c_int,
+        nblock: c_int,
+    ) -> c_int;
+    fn fits_rcomp_byte(
+        a: *const c_schar,
+        nx: c_int,
+        c: *mut c_uchar,
+        clen: c_int,
+        nblock: c_int,
+    ) -> c_int;
+
+    fn fits_rdecomp(
+        c: *const c_uchar,
+        clen: c_int,
+        array: *mut c_uint,
+        nx: c_int,
+        nblock: c_int,
+    ) -> c_int;
+    fn fits_rdecomp_short(
+        c: *const c_uchar,
+        clen: c_int,
+        array: *mut c_ushort,
+        nx: c_int,
+        nblock: c_int,
+    ) -> c_int;
+    fn fits_rdecomp_byte(
+        c: *const c_uchar,
+        clen: c_int,
+        array: *mut c_uchar,
+        nx: c_int,
+        nblock: c_int,
+    ) -> c_int;
+}
+
+/// Output capacity handed to the C encoder, which does not check for overflow
+/// itself: every pixel directly coded, plus block headers and the first value.
+fn capacity(nx: usize, nblock: usize, bytepix: usize) -> usize {
+    nx * bytepix + nx.div_ceil(nblock) + bytepix + 8
+}
+
+/// Lengths and block size as C ints, or `None` if they do not fit. The C
+/// encoder reads the first pixel and loops forever on a zero block size, so
+/// both must be nonzero.
+fn c_sizes(nx: usize, clen: usize, nblock: usize) -> Option<(c_int, c_int, c_int)> {
+    if nx == 0 || nblock == 0 {
+        return None;
+    }
+    Some((
+        c_int::try_from(nx).ok()?,
+        c_int::try_from(clen).ok()?,
+        c_int::try_from(nblock).ok()?,
+    ))
+}
+
+/// Whether the C decoder can safely be handed `input`. It reads the first
+/// value and the byte after it unchecked, and only checks for running off the
+/// end of the input after each block, so anything but a well formed stream
+/// could be read out of bounds.
+fn decodable(input: &[u8], nx: usize, nblock: usize, width: SampleWidth) -> bool {
+    input.len() > width.bytepix() && RCDecoder::new().validate(input, nx, nblock, width).is_ok()
+}
+
+/// A sample width implemented by `ricecomp.c`
+pub trait CSample: Copy + Default {
+    /// `fits_rcomp`, `fits_rcomp_short` or `fits_rcomp_byte`
+    fn c_encode(input: &[Self], nblock: usize) -> Option<Vec<u8>>;
+
+    /// `fits_rdecomp`, `fits_rdecomp_short` or `fits_rdecomp_byte`. Returns
+    /// `None` without calling C if `input` is not a well formed stream.
+    fn c_decode(input: &[u8], nx: usize, nblock: usize) -> Option<Vec<Self>>;
+}
+
+impl CSample for i32 {
+    fn c_encode(input: &[Self], nblock: usize) -> Option<Vec<u8>> {
+        let mut out = vec![0u8; capacity(input.len(), nblock, SampleWidth::Int.bytepix())];
+        let (nx, clen, nblock) = c_sizes(input.len(), out.len(), nblock)?;
+        // SAFETY: `input` holds `nx` pixels, at least one, and `out` holds
+        // `clen` bytes, room for the longest stream `nx` pixels can be coded
+        // as, so the encoder stays within both even though it only checks the
+        // output length after each block.
+        let n = unsafe { fits_rcomp(input.as_ptr(), nx, out.as_mut_ptr(), clen, nblock) };
+        out.truncate(usize::try_from(n).ok()?);
+        Some(out)
+    }
+
+    fn c_decode(input: &[u8], nx: usize, nblock: usize) -> Option<Vec<Self>> {
+        if !decodable(input, nx, nblock, SampleWidth::Int) {
+            return None;
+        }
+        let mut out = vec![0 as c_uint; nx];
+        let (c_nx, clen, c_nblock) = c_sizes(nx, input.len(), nblock)?;
+        // SAFETY: `input` holds `clen` bytes and was checked to be a well
+        // formed stream of `nx` pixels, so every byte the decoder reads lies
+        // within it; `out` holds the `nx` pixels it writes.
+        let status =
+            unsafe { fits_rdecomp(input.as_ptr(), clen, out.as_mut_ptr(), c_nx, c_nblock) };
+        (status == 0).then(|| out.into_iter().map(|v| v as i32).collect())
+    }
+}
+
+impl CSample for i16 {
+    fn c_encode(input: &[Self], nblock: usize) -> Option<Vec<u8>> {
+        let mut out = vec![0u8; capacity(input.len(), nblock, SampleWidth::Short.bytepix())];
+        let (nx, clen, nblock) = c_sizes(input.len(), out.len(), nblock)?;
+        // SAFETY: `input` holds `nx` pixels, at least one, and `out` holds
+        // `clen` bytes, room for the longest stream `nx` pixels can be coded
+        // as, so the encoder stays within both even though it only checks the
+        // output length after each block.
+        let n = unsafe { fits_rcomp_short(input.as_ptr(), nx, out.as_mut_ptr(), clen, nblock) };
+        out.truncate(usize::try_from(n).ok()?);
+        Some(out)
+    }
+
+    fn c_decode(input: &[u8], nx: usize, nblock: usize) -> Option<Vec<Self>> {
+        if !decodable(input, nx, nblock, SampleWidth::Short) {
+            return None;
+        }
+        let mut out = vec![0 as c_ushort; nx];
+        let (c_nx, clen, c_nblock) = c_sizes(nx, input.len(), nblock)?;
+        // SAFETY: `input` holds `clen` bytes and was checked to be a well
+        // formed stream of `nx` pixels, so every byte the decoder reads lies
+        // within it; `out` holds the `nx` pixels it writes.
+        let status =
+            unsafe { fits_rdecomp_short(input.as_ptr(), clen, out.as_mut_ptr(), c_nx, c_nblock) };
+        (status == 0).then(|| out.into_iter().map(|v| v as i16).collect())
+    }
+}
+
+impl CSample for i8 {
+    fn c_encode(input: &[Self], nblock: usize) -> Option<Vec<u8>> {
+        let mut out = vec![0u8; capacity(input.len(), nblock, SampleWidth::Byte.bytepix())];
+        let (nx, clen, nblock) = c_sizes(input.len(), out.len(), nblock)?;
+        // SAFETY: `input` holds `nx` pixels, at least one, and `out` holds
+        // `clen` bytes, room for the longest stream `nx` pixels can be coded
+        // as, so the encoder stays within both even though it only checks the
+        // output length after each block.
+        let n = unsafe { fits_rcomp_byte(input.as_ptr(), nx, out.as_mut_ptr(), clen, nblock) };
+        out.truncate(usize::try_from(n).ok()?);
+        Some(out)
+    }
+
+    fn c_decode(input: &[u8], nx: usize, nblock: usize) -> Option<Vec<Self>> {
+        if !decodable(input, nx, nblock, SampleWidth::Byte) {
+            return None;
+        }
+        let mut out = vec![0 as c_uchar; nx];
+        let (c_nx, clen, c_nblock) = c_sizes(nx, input.len(), nblock)?;
+        // SAFETY: `input` holds `clen` bytes and was checked to be a well
+        // formed stream of `nx` pixels, so every byte the decoder reads lies
+        // within it; `out` holds the `nx` pixels it writes.
+        let status =
+            unsafe { fits_rdecomp_byte(input.as_ptr(), clen, out.as_mut_ptr(), c_nx, c_nblock) };
+        (status == 0).then(|| out.into_iter().map(|v| v as i8).collect())
+    }
+}
@@ -0,0 +1,161 @@
+use ricecomp::{
+    read::RCDecoder,
+    sample::RiceSample,
+    write::{FsStrategy, RCEncoder},
+};
+use ricecomp_compat::CSample;
+
+const BLOCK_SIZES: [usize; 10] = [1, 2, 3, 7, 16, 31, 32, 64, 139, 257];
+
+/// Small deterministic generator so failures reproduce without a seed file
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Inputs as 64-bit values, truncated to the sample width by the caller
+fn inputs(rng: &mut XorShift) -> Vec<Vec<i64>> {
+    let mut cases = vec![
+        vec![0],
+        vec![-1; 300],
+        [i64::MIN, i64::MAX].repeat(70),
+        (0..500).collect(),
+        (0..500).map(|x| x * x).collect(),
+        (0..300)
+            .map(|x| if x % 37 == 0 { -1 << 30 } else { -1 })
+            .collect(),
+    ];
+
+    for _ in 0..200 {
+        let n = 1 + rng.below(700) as usize;
+        let bits = rng.below(33) as u32;
+        let outliers = rng.below(4);
+        let mut last = rng.next() as i64;
+        let case = (0..n)
+            .map(|_| {
+                if rng.below(100) < outliers {
+                    /* sparse extreme values */
+                    rng.next() as i64
+                } else if bits == 0 {
+                    last
+                } else {
+                    /* random walk with steps of up to `bits` bits */
+                    let step = (rng.next() >> (64 - bits)) as i64 - (1 << (bits - 1));
+                    last = last.wrapping_add(step);
+                    last
+                }
+            })
+            .collect();
+        cases.push(case);
+    }
+    cases
+}
+
+/// Compare both encoders and both decoders on one input, returning false if
+/// the case had to be skipped because CFITSIO cannot round-trip it.
+fn compare<T>(input: &[T], nblock: usize) -> bool
+where
+    T: RiceSample + CSample + PartialEq + std::fmt::Debug,
+{
+    let c_encoded = T::c_encode(input, nblock).expect("C encoder failed");
+
+    let mut rust_encoded = Vec::new();
+    RCEncoder::new(&mut rust_encoded)
+        .encode(input, input.len(), nblock)
+        .unwrap();
+
+    /* CFITSIO must always be able to read what the Rust port writes */
+    assert_eq!(
+        T::c_decode(&rust_encoded, input.len(), nblock).as_deref(),
+        Some(input),
+        "C cannot decode Rust output for {:?} with nblock {}",
+        input,
+        nblock
+    );
+
+    /* nor what it writes when choosing fs optimally */
+    let mut optimal = Vec::new();
+    let mut encoder = RCEncoder::new(&mut optimal);
+    encoder.set_fs_strategy(FsStrategy::Optimal);
+    encoder.encode(input, input.len(), nblock).unwrap();
+    assert_eq!(
+        T::c_decode(&optimal, input.len(), nblock).as_deref(),
+        Some(input),
+        "C cannot decode optimal Rust output for {:?} with nblock {}",
+        input,
+        nblock
+    );
+
+    let c_roundtrip = T::c_decode(&c_encoded, input.len(), nblock);
+    if c_roundtrip.as_deref() != Some(input) {
+        /*
+         * ricecomp.c shares the bug fixed in the Rust port where mapped
+         * differences >= 2^31 in a Rice coded block go negative, so its
+         * output is not comparable for those inputs.
+         */
+        return false;
+    }
+
+    assert_eq!(
+        c_encoded, rust_encoded,
+        "encoded bytes differ for {:?} with nblock {}",
+        input, nblock
+    );
+
+    let mut rust_decoded = vec![T::default(); input.len()];
+    RCDecoder::new()
+        .decode(&c_encoded, input.len(), nblock, &mut rust_decoded)
+        .unwrap();
+    assert_eq!(rust_decoded, input);
+    true
+}
+
+fn compare_all<T>(truncate: fn(i64) -> T)
+where
+    T: RiceSample + CSample + PartialEq + std::fmt::Debug,
+{
+    let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+    let mut compared = 0;
+    let mut skipped = 0;
+    for case in inputs(&mut rng) {
+        let input: Vec<T> = case.into_iter().map(truncate).collect();
+        for nblock in BLOCK_SIZES {
+            if compare(&input, nblock) {
+                compared += 1;
+            } else {
+                skipped += 1;
+            }
+        }
+    }
+    assert!(
+        skipped * 10 < compared,
+        "too many skipped cases: {} skipped, {} compared",
+        skipped,
+        compared
+    );
+}
+
+#[test]
+fn int_matches_cfitsio() {
+    compare_all(|x| x as i32);
+}
+
+#[test]
+fn short_matches_cfitsio() {
+    compare_all(|x| x as i16);
+}
+
+#[test]
+fn byte_matches_cfitsio() {
+    compare_all(|x| x as i8);
+}
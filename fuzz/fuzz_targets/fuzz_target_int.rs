@@ -12,16 +12,15 @@ fuzz_target!(|data: DataInt| {
     let blocksz = 32;
 
 
-    let mut encoder = RCEncoder::new();
     let mut comp_array = Vec::new();
-    let out_count = encoder.encode(&data.d, l, blocksz as usize, &mut comp_array);
+    let mut encoder = RCEncoder::new(&mut comp_array);
+    let out_count = encoder.encode(&data.d, l, blocksz as usize);
 
     match out_count {
         Ok(v) => {
             let decoder = RCDecoder::new();
             let mut decomp_array = vec![0; l];
             let result = decoder.decode(&comp_array,l, blocksz as usize, &mut decomp_array).unwrap();
-            let decomp_array: Vec<i32> = decomp_array.iter().map(|&x| x as i32).collect();
 
             assert_eq!(data.d, decomp_array);
         },
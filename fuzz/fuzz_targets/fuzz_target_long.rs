@@ -0,0 +1,33 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+extern crate ricecomp;
+use crate::ricecomp::DataLong;
+use ricecomp::read::RCDecoder;
+use ricecomp::write::RCEncoder;
+
+fuzz_target!(|data: DataLong| {
+    let l = data.d.len();
+   // let blocksz = if data.bs > 64 { (data.bs % 64)+1 } else {data.bs};
+    //let blocksz = if blocksz < 1 { 1 } else { blocksz };
+    let blocksz = 32;
+
+
+    let mut comp_array = Vec::new();
+    let mut encoder = RCEncoder::new(&mut comp_array);
+    let out_count = encoder.encode_long(&data.d, l, blocksz as usize);
+
+    match out_count {
+        Ok(v) => {
+            let decoder = RCDecoder::new();
+            let mut decomp_array = vec![0; l];
+            let result = decoder.decode_long(&comp_array,l, blocksz as usize, &mut decomp_array).unwrap();
+
+            assert_eq!(data.d, decomp_array);
+        },
+
+        Err(v) => {
+
+        }
+    }
+    
+});
@@ -12,16 +12,15 @@ fuzz_target!(|data: DataShort| {
     let blocksz = 32;
 
 
-    let mut encoder = RCEncoder::new();
     let mut comp_array = Vec::new();
-    let out_count = encoder.encode_short(&data.d, l, blocksz as usize, &mut comp_array);
+    let mut encoder = RCEncoder::new(&mut comp_array);
+    let out_count = encoder.encode_short(&data.d, l, blocksz as usize);
 
     match out_count {
         Ok(v) => {
             let decoder = RCDecoder::new();
             let mut decomp_array = vec![0; l];
             let result = decoder.decode_short(&comp_array,l, blocksz as usize, &mut decomp_array).unwrap();
-            let decomp_array: Vec<i16> = decomp_array.iter().map(|&x| x as i16).collect();
 
             assert_eq!(data.d, decomp_array);
         },
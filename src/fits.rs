@@ -0,0 +1,1197 @@
+//! Reading and writing Rice compressed images in FITS files.
+//!
+//! The FITS tiled image convention stores a compressed image in a binary
+//! table extension marked `ZIMAGE = T`. The image is cut into rectangular
+//! tiles of `ZTILEn` pixels, each tile is Rice coded on its own and the coded
+//! bytes live in the heap, pointed to by one variable-length array descriptor
+//! per table row in the `COMPRESSED_DATA` column. The original image keeps
+//! its shape and type in the `ZBITPIX` and `ZNAXISn` keywords, and the Rice
+//! parameters are stored as `ZNAME1 = 'BLOCKSIZE'` and `ZNAME2 = 'BYTEPIX'`
+//! with their values in `ZVAL1` and `ZVAL2`.
+//!
+//! Tiles may be Rice coded at a different width than the image, for example
+//! a 32-bit image whose values all fit in 16 bits as `BYTEPIX = 2`; they are
+//! widened back to `ZBITPIX` on reading.
+//!
+//! `ZBITPIX = -32` images are quantized tile by tile with the
+//! [`quantize`](crate::quantize) module, recording each tile's scaling in the
+//! `ZSCALE` and `ZZERO` columns. A tile that cannot be quantized, such as one
+//! with no noise, is stored as it is in the `UNCOMPRESSED_DATA` column.
+//!
+//! Null pixels are marked by the `ZBLANK` keyword. In integer images they
+//! are Rice coded like any other pixel, so the tiles are exactly what
+//! CFITSIO writes and reads; the null mask of
+//! [`RCEncoder::encode_nulls`](crate::write::RCEncoder::encode_nulls) has no
+//! place in the convention and is not used here. NaN pixels of floating
+//! point images are quantized to a reserved value next to the rest of the
+//! tile, which keeps them out of the differences as CFITSIO does. A `ZBLANK`
+//! column giving the value per tile is not read.
+//!
+//! Only `ZCMPTYPE = 'RICE_1'` with 8, 16 and 32-bit integer and 32-bit
+//! floating point images is handled. Tiles stored in other columns, such as
+//! `GZIP_COMPRESSED_DATA`, are rejected.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::{
+    quantize::{Dither, Quantizer, TileScale, NULL_VALUE},
+    read::{DecodeError, RCDecoder},
+    sample::{RiceSample, SampleWidth},
+    write::{EncodeError, RCEncoder},
+};
+
+/// FITS files are made of blocks of this many bytes
+pub const BLOCK_LEN: usize = 2880;
+/// Size in bytes of one header keyword record
+const CARD_LEN: usize = 80;
+
+/// Default number of pixels per coding block, as used by CFITSIO
+pub const DEFAULT_BLOCKSIZE: usize = 32;
+
+/// Largest number of axes a FITS image may have
+const MAX_NAXIS: usize = 999;
+/// Largest number of columns a FITS table may have
+const MAX_TFIELDS: usize = 999;
+/// Largest `BLOCKSIZE` handled. An all zero block costs only a few bits,
+/// so a larger one would let a tiny heap claim a huge tile.
+const MAX_BLOCKSIZE: usize = 1 << 16;
+/// Smallest table row: the `1PB` descriptor of the `COMPRESSED_DATA` column
+const MIN_ROW_LEN: usize = 8;
+
+#[derive(Debug)]
+pub enum FitsError {
+    /// Writing the file failed
+    Io(io::Error),
+    Encode(EncodeError),
+    Decode(DecodeError),
+    /// The input ended inside a header or data unit
+    Truncated,
+    /// A mandatory keyword is absent
+    MissingKeyword(String),
+    /// A keyword has a malformed or out of range value
+    InvalidKeyword(String),
+    /// Valid FITS that this module does not handle, such as another
+    /// compression algorithm
+    Unsupported(String),
+    /// No header data unit has `ZIMAGE = T`
+    NoCompressedImage,
+    /// The heap descriptor of tile `tile` points outside the heap, or at too
+    /// few bytes to hold the tile's pixels
+    BadDescriptor {
+        tile: usize,
+    },
+    /// The number of pixels does not match the image dimensions
+    DimensionMismatch {
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for FitsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FitsError::Io(err) => write!(f, "error writing output: {}", err),
+            FitsError::Encode(err) => write!(f, "error compressing tile: {}", err),
+            FitsError::Decode(err) => write!(f, "error decompressing tile: {}", err),
+            FitsError::Truncated => write!(f, "FITS file is truncated"),
+            FitsError::MissingKeyword(keyword) => write!(f, "missing keyword {}", keyword),
+            FitsError::InvalidKeyword(keyword) => {
+                write!(f, "invalid value for keyword {}", keyword)
+            }
+            FitsError::Unsupported(what) => write!(f, "unsupported {}", what),
+            FitsError::NoCompressedImage => write!(f, "no compressed image found"),
+            FitsError::BadDescriptor { tile } => {
+                write!(
+                    f,
+                    "heap descriptor of tile {} is out of range or too short",
+                    tile
+                )
+            }
+            FitsError::DimensionMismatch { expected, found } => write!(
+                f,
+                "image dimensions need {} pixels, found {}",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FitsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FitsError::Io(err) => Some(err),
+            FitsError::Encode(err) => Some(err),
+            FitsError::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FitsError {
+    fn from(err: io::Error) -> Self {
+        FitsError::Io(err)
+    }
+}
+
+impl From<EncodeError> for FitsError {
+    fn from(err: EncodeError) -> Self {
+        FitsError::Encode(err)
+    }
+}
+
+impl From<DecodeError> for FitsError {
+    fn from(err: DecodeError) -> Self {
+        FitsError::Decode(err)
+    }
+}
+
+/// Pixel values of an image, in FITS order with the first axis varying
+/// fastest. The variant gives `ZBITPIX`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImageData {
+    /// `ZBITPIX = 8`, which FITS defines as unsigned
+    U8(Vec<u8>),
+    /// `ZBITPIX = 16`
+    I16(Vec<i16>),
+    /// `ZBITPIX = 32`
+    I32(Vec<i32>),
+    /// `ZBITPIX = -32`, quantized to integers before Rice coding
+    F32(Vec<f32>),
+}
+
+impl ImageData {
+    /// The FITS `BITPIX` value for this pixel type
+    pub fn bitpix(&self) -> i64 {
+        match self {
+            ImageData::U8(_) => 8,
+            ImageData::I16(_) => 16,
+            ImageData::I32(_) => 32,
+            ImageData::F32(_) => -32,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ImageData::U8(pixels) => pixels.len(),
+            ImageData::I16(pixels) => pixels.len(),
+            ImageData::I32(pixels) => pixels.len(),
+            ImageData::F32(pixels) => pixels.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Shape and compression parameters of a tile compressed image
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZImageHeader {
+    /// Image size along each axis, `ZNAXISn`
+    pub naxes: Vec<usize>,
+    /// Tile size along each axis, `ZTILEn`
+    pub tile: Vec<usize>,
+    /// Pixels per Rice coding block, `BLOCKSIZE`, at most 65536
+    pub blocksize: usize,
+    /// Width the tiles are Rice coded at, `BYTEPIX`. When writing, `None`
+    /// picks the narrowest width that holds every pixel of the image, which
+    /// may be narrower than `ZBITPIX`.
+    pub bytepix: Option<SampleWidth>,
+    /// How floating point images are quantized, `ZQUANTIZ` and `ZDITHER0`.
+    /// The level `q` is not stored in the file and reads back as
+    /// [`DEFAULT_QLEVEL`](crate::quantize::DEFAULT_QLEVEL).
+    pub quantize: Quantizer,
+    /// Value marking null pixels of an integer image, `ZBLANK`. The nulls
+    /// are coded as they are, so CFITSIO reads the same pixels back.
+    pub blank: Option<i32>,
+}
+
+impl ZImageHeader {
+    /// Header for an image of the given size, compressed one row per tile
+    /// with the default block size.
+    pub fn new(naxes: Vec<usize>) -> ZImageHeader {
+        let tile = naxes
+            .iter()
+            .enumerate()
+            .map(|(axis, &n)| if axis == 0 { n } else { 1 })
+            .collect();
+        ZImageHeader {
+            naxes,
+            tile,
+            blocksize: DEFAULT_BLOCKSIZE,
+            bytepix: None,
+            quantize: Quantizer::default(),
+            blank: None,
+        }
+    }
+
+    /// Number of pixels in the image, saturating at `usize::MAX` for sizes
+    /// too large to be written or read
+    pub fn pixels(&self) -> usize {
+        self.naxes.iter().fold(1, |n, &axis| n.saturating_mul(axis))
+    }
+
+    /// Number of tiles, which is the number of rows of the table, saturating
+    /// like [`pixels`](Self::pixels)
+    pub fn tiles(&self) -> usize {
+        self.naxes
+            .iter()
+            .zip(&self.tile)
+            .fold(1, |n, (&axis, &tile)| n.saturating_mul(axis.div_ceil(tile)))
+    }
+
+    fn check(&self) -> Result<(), FitsError> {
+        if self.naxes.is_empty() || self.naxes.len() > MAX_NAXIS || self.naxes.contains(&0) {
+            return Err(FitsError::InvalidKeyword("ZNAXIS".to_string()));
+        }
+        /* the sizes may come from a header, so their products can overflow */
+        let pixels = self
+            .naxes
+            .iter()
+            .try_fold(1usize, |n, &axis| n.checked_mul(axis));
+        if pixels.is_none() {
+            return Err(FitsError::InvalidKeyword("ZNAXIS".to_string()));
+        }
+        if self.tile.len() != self.naxes.len() || self.tile.contains(&0) {
+            return Err(FitsError::InvalidKeyword("ZTILE".to_string()));
+        }
+        if self.blocksize == 0 || self.blocksize > MAX_BLOCKSIZE {
+            return Err(FitsError::InvalidKeyword("ZVAL1".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Origin and size of each tile, in table row order
+    fn tile_regions(&self) -> Vec<(Vec<usize>, Vec<usize>)> {
+        let ndim = self.naxes.len();
+        let mut regions = Vec::with_capacity(self.tiles());
+        let mut origin = vec![0; ndim];
+        loop {
+            let shape = (0..ndim)
+                .map(|axis| self.tile[axis].min(self.naxes[axis] - origin[axis]))
+                .collect();
+            regions.push((origin.clone(), shape));
+
+            /* step to the next tile, first axis fastest */
+            let mut axis = 0;
+            loop {
+                if axis == ndim {
+                    return regions;
+                }
+                origin[axis] += self.tile[axis];
+                if origin[axis] < self.naxes[axis] {
+                    break;
+                }
+                origin[axis] = 0;
+                axis += 1;
+            }
+        }
+    }
+
+    /// Call `f` with the image offset and length of each run of pixels along
+    /// the first axis within a tile, in the order the tile stores them
+    fn for_each_run(&self, origin: &[usize], shape: &[usize], mut f: impl FnMut(usize, usize)) {
+        let ndim = self.naxes.len();
+        let mut index = vec![0; ndim];
+        loop {
+            let mut offset = 0;
+            for axis in (0..ndim).rev() {
+                offset = offset * self.naxes[axis] + origin[axis] + index[axis];
+            }
+            f(offset, shape[0]);
+
+            let mut axis = 1;
+            loop {
+                if axis >= ndim {
+                    return;
+                }
+                index[axis] += 1;
+                if index[axis] < shape[axis] {
+                    break;
+                }
+                index[axis] = 0;
+                axis += 1;
+            }
+        }
+    }
+
+    /// Copy the pixels of one tile out of the image, in tile order
+    fn gather<T: Copy>(&self, pixels: &[T], origin: &[usize], shape: &[usize], tile: &mut Vec<T>) {
+        tile.clear();
+        self.for_each_run(origin, shape, |offset, len| {
+            tile.extend_from_slice(&pixels[offset..offset + len])
+        });
+    }
+
+    /// Copy the pixels of one tile back into the image
+    fn scatter<T: Copy>(&self, tile: &[T], origin: &[usize], shape: &[usize], pixels: &mut [T]) {
+        let mut runs = tile.chunks(shape[0]);
+        self.for_each_run(origin, shape, |offset, len| {
+            pixels[offset..offset + len].copy_from_slice(runs.next().unwrap())
+        });
+    }
+
+    /// One past the image offset of the last pixel of a tile
+    fn tile_end(&self, origin: &[usize], shape: &[usize]) -> usize {
+        let mut end = 0;
+        for axis in (0..self.naxes.len()).rev() {
+            end = end * self.naxes[axis] + origin[axis] + shape[axis] - 1;
+        }
+        end + 1
+    }
+
+    /// Width to code the tiles at: the requested one, or the narrowest that
+    /// holds all the values the tiles are coded from
+    fn coding_width(&self, narrowest: SampleWidth) -> Result<SampleWidth, FitsError> {
+        let width = self.bytepix.unwrap_or(narrowest);
+        if width.bytepix() < narrowest.bytepix() {
+            return Err(FitsError::InvalidKeyword("ZVAL2".to_string()));
+        }
+        Ok(width)
+    }
+
+    /// Rice code each tile of an integer image into the heap
+    fn encode_integer<T: RiceSample + PartialEq>(
+        &self,
+        pixels: &[T],
+        heap: &mut Vec<u8>,
+    ) -> Result<(Vec<TileRow>, SampleWidth), FitsError> {
+        let width = self.coding_width(SampleWidth::narrowest(pixels))?;
+        let mut rows = Vec::with_capacity(self.tiles());
+        let mut tile = Vec::new();
+        for (origin, shape) in self.tile_regions() {
+            self.gather(pixels, &origin, &shape, &mut tile);
+            let start = heap.len();
+            let len =
+                RCEncoder::new(&mut *heap).encode_as(&tile, tile.len(), self.blocksize, width)?;
+            rows.push(TileRow {
+                compressed: (len, start),
+                ..TileRow::default()
+            });
+        }
+        Ok((rows, width))
+    }
+
+    /// Quantize and Rice code each tile of a floating point image into the
+    /// heap. Tiles that cannot be quantized are stored as they are.
+    fn encode_float(
+        &self,
+        pixels: &[f32],
+        heap: &mut Vec<u8>,
+    ) -> Result<(Vec<TileRow>, SampleWidth), FitsError> {
+        let mut tiles = Vec::with_capacity(self.tiles());
+        let mut narrowest = SampleWidth::Byte;
+        let mut tile = Vec::new();
+        for (index, (origin, shape)) in self.tile_regions().iter().enumerate() {
+            self.gather(pixels, origin, shape, &mut tile);
+            let mut quantized = vec![0; tile.len()];
+            match self
+                .quantize
+                .quantize(index, &tile, shape[0], &mut quantized)
+            {
+                Some(scale) => {
+                    let width = SampleWidth::narrowest(&quantized);
+                    if width.bytepix() > narrowest.bytepix() {
+                        narrowest = width;
+                    }
+                    tiles.push(Ok((quantized, scale)));
+                }
+                None => tiles.push(Err(tile.clone())),
+            }
+        }
+
+        let width = self.coding_width(narrowest)?;
+        let mut rows = Vec::with_capacity(tiles.len());
+        for tile in tiles {
+            let start = heap.len();
+            rows.push(match tile {
+                Ok((quantized, scale)) => {
+                    let len = RCEncoder::new(&mut *heap).encode_as(
+                        &quantized,
+                        quantized.len(),
+                        self.blocksize,
+                        width,
+                    )?;
+                    TileRow {
+                        compressed: (len, start),
+                        uncompressed: (0, 0),
+                        scale: Some(scale),
+                    }
+                }
+                Err(raw) => {
+                    for value in &raw {
+                        heap.extend_from_slice(&value.to_be_bytes());
+                    }
+                    TileRow {
+                        compressed: (0, 0),
+                        uncompressed: (raw.len(), start),
+                        scale: None,
+                    }
+                }
+            });
+        }
+        Ok((rows, width))
+    }
+
+    /// Decode the Rice coded tiles of an integer image
+    fn decode_integer<T: RiceSample>(
+        &self,
+        table: &Table,
+        heap: &[u8],
+    ) -> Result<Vec<T>, FitsError> {
+        let width = self.bytepix.unwrap_or(T::WIDTH);
+        let decoder = RCDecoder::new();
+        /* the size comes from the header, so the image grows as its tiles
+         * are decoded rather than being allocated up front */
+        let mut pixels = Vec::new();
+        for (index, (origin, shape)) in self.tile_regions().iter().enumerate() {
+            let bytes = table.compressed(index, heap)?.ok_or_else(|| {
+                FitsError::Unsupported(format!("tile {} without COMPRESSED_DATA", index))
+            })?;
+            let len = shape.iter().product();
+            if len > max_tile_pixels(bytes.len(), width, self.blocksize) {
+                return Err(FitsError::BadDescriptor { tile: index });
+            }
+            let tile = decoder.decode_vec_as::<T>(bytes, len, self.blocksize, width)?;
+            let end = self.tile_end(origin, shape).max(pixels.len());
+            pixels.resize(end, T::default());
+            self.scatter(&tile, origin, shape, &mut pixels);
+        }
+        Ok(pixels)
+    }
+
+    /// Decode and restore the quantized tiles of a floating point image, or
+    /// read tiles that were stored as they are
+    fn decode_float(&self, table: &Table, heap: &[u8]) -> Result<Vec<f32>, FitsError> {
+        let width = self.bytepix.unwrap_or(SampleWidth::Int);
+        let decoder = RCDecoder::new();
+        let mut pixels = Vec::new();
+        let mut tile = Vec::new();
+        for (index, (origin, shape)) in self.tile_regions().iter().enumerate() {
+            let len = shape.iter().product();
+            let compressed = table.compressed(index, heap)?;
+            let max_len = match compressed {
+                Some(bytes) => max_tile_pixels(bytes.len(), width, self.blocksize),
+                None => heap.len() / 4,
+            };
+            if len > max_len {
+                return Err(FitsError::BadDescriptor { tile: index });
+            }
+            match compressed {
+                Some(bytes) => {
+                    let quantized = decoder.decode_vec_as(bytes, len, self.blocksize, width)?;
+                    tile.resize(len, 0.0);
+                    let scale = table.scale(index)?;
+                    self.quantize
+                        .dequantize(index, &quantized, scale, &mut tile);
+                }
+                None => {
+                    tile.resize(len, 0.0);
+                    table.uncompressed(index, heap, &mut tile)?;
+                }
+            }
+            let end = self.tile_end(origin, shape).max(pixels.len());
+            pixels.resize(end, 0.0);
+            self.scatter(&tile, origin, shape, &mut pixels);
+        }
+        Ok(pixels)
+    }
+
+    /// Read the image keywords of a `ZIMAGE = T` header
+    fn from_header(header: &Header) -> Result<ZImageHeader, FitsError> {
+        let cmptype = header.required_str("ZCMPTYPE")?;
+        if cmptype != "RICE_1" && cmptype != "RICE_ONE" {
+            return Err(FitsError::Unsupported(format!(
+                "compression type {}",
+                cmptype
+            )));
+        }
+
+        let naxis = header.required_size("ZNAXIS")?;
+        if naxis == 0 || naxis > MAX_NAXIS {
+            return Err(FitsError::InvalidKeyword("ZNAXIS".to_string()));
+        }
+        let mut naxes = Vec::with_capacity(naxis);
+        let mut tile = Vec::with_capacity(naxis);
+        for axis in 1..=naxis {
+            let n = header.required_size(&format!("ZNAXIS{}", axis))?;
+            naxes.push(n);
+            tile.push(
+                header
+                    .size(&format!("ZTILE{}", axis))?
+                    .unwrap_or(if axis == 1 { n } else { 1 }),
+            );
+        }
+
+        /* without a BYTEPIX parameter tiles are coded at the image width */
+        let bitpix = header.required_int("ZBITPIX")?;
+        let mut zimage = ZImageHeader {
+            naxes,
+            tile,
+            blocksize: DEFAULT_BLOCKSIZE,
+            bytepix: Some(bitpix_width(bitpix)?),
+            quantize: Quantizer::default(),
+            blank: None,
+        };
+        for (name, value) in header.parameters() {
+            if name.eq_ignore_ascii_case("BLOCKSIZE") {
+                zimage.blocksize = header.required_size(&value)?;
+            } else if name.eq_ignore_ascii_case("BYTEPIX") {
+                let bytepix = header.required_size(&value)?;
+                zimage.bytepix = Some(
+                    SampleWidth::from_bytepix(bytepix).ok_or(FitsError::InvalidKeyword(value))?,
+                );
+            }
+        }
+        if bitpix < 0 {
+            /* files without ZQUANTIZ predate dithering */
+            zimage.quantize.dither = match header.str("ZQUANTIZ") {
+                Some(method) => Dither::from_keyword(method)
+                    .ok_or_else(|| FitsError::Unsupported(format!("ZQUANTIZ {}", method)))?,
+                None => Dither::None,
+            };
+            if let Some(seed) = header.size("ZDITHER0")? {
+                zimage.quantize.seed = seed;
+            }
+        } else {
+            /* older writers copy the image's BLANK keyword instead */
+            let blank = match header.int("ZBLANK")? {
+                Some(blank) => Some(blank),
+                None => header.int("BLANK")?,
+            };
+            zimage.blank = blank
+                .map(|blank| {
+                    i32::try_from(blank)
+                        .map_err(|_| FitsError::InvalidKeyword("ZBLANK".to_string()))
+                })
+                .transpose()?;
+        }
+        zimage.check()?;
+        Ok(zimage)
+    }
+}
+
+/// Write a primary header with no data, which must start every FITS file
+/// before any extension
+pub fn write_primary<W: Write>(mut out: W) -> Result<(), FitsError> {
+    let mut header = HeaderWriter::default();
+    header.card(
+        "SIMPLE",
+        Value::Logical(true),
+        "file conforms to FITS standard",
+    );
+    header.card("BITPIX", Value::Int(8), "");
+    header.card("NAXIS", Value::Int(0), "no data in the primary array");
+    header.card("EXTEND", Value::Logical(true), "file contains extensions");
+    out.write_all(&header.finish())?;
+    Ok(())
+}
+
+/// Tile compress `data` and write it as a `ZIMAGE = T` binary table
+/// extension, padded to a whole number of FITS blocks.
+pub fn write_zimage<W: Write>(
+    mut out: W,
+    zimage: &ZImageHeader,
+    data: &ImageData,
+) -> Result<(), FitsError> {
+    zimage.check()?;
+    if data.len() != zimage.pixels() {
+        return Err(FitsError::DimensionMismatch {
+            expected: zimage.pixels(),
+            found: data.len(),
+        });
+    }
+
+    let mut heap = Vec::new();
+    let (rows, width) = match data {
+        ImageData::U8(pixels) => zimage.encode_integer(pixels, &mut heap)?,
+        ImageData::I16(pixels) => zimage.encode_integer(pixels, &mut heap)?,
+        ImageData::I32(pixels) => zimage.encode_integer(pixels, &mut heap)?,
+        ImageData::F32(pixels) => zimage.encode_float(pixels, &mut heap)?,
+    };
+    let float = matches!(data, ImageData::F32(_));
+
+    /* 32-bit descriptors unless the heap outgrows them */
+    let wide = heap.len() > i32::MAX as usize;
+    let descriptor = if wide { 'Q' } else { 'P' };
+    let maxlen = |len: fn(&TileRow) -> usize| rows.iter().map(len).max().unwrap_or(0);
+    let mut columns = vec![(
+        "COMPRESSED_DATA",
+        format!("1{}B({})", descriptor, maxlen(|row| row.compressed.0)),
+    )];
+    let uncompressed = rows.iter().any(|row| row.uncompressed.0 > 0);
+    if uncompressed {
+        columns.push((
+            "UNCOMPRESSED_DATA",
+            format!("1{}E({})", descriptor, maxlen(|row| row.uncompressed.0)),
+        ));
+    }
+    if float {
+        columns.push(("ZSCALE", "1D".to_string()));
+        columns.push(("ZZERO", "1D".to_string()));
+    }
+    let row_len: usize = columns
+        .iter()
+        .map(|(_, tform)| tform_width(tform).unwrap())
+        .sum();
+
+    let mut header = HeaderWriter::default();
+    header.card("XTENSION", Value::Str("BINTABLE"), "binary table extension");
+    header.card("BITPIX", Value::Int(8), "");
+    header.card("NAXIS", Value::Int(2), "");
+    header.card(
+        "NAXIS1",
+        Value::Int(row_len as i64),
+        "width of table in bytes",
+    );
+    header.card("NAXIS2", Value::Int(rows.len() as i64), "number of tiles");
+    header.card("PCOUNT", Value::Int(heap.len() as i64), "size of the heap");
+    header.card("GCOUNT", Value::Int(1), "");
+    header.card("TFIELDS", Value::Int(columns.len() as i64), "");
+    for (field, (name, tform)) in columns.iter().enumerate() {
+        header.card(&format!("TTYPE{}", field + 1), Value::Str(name), "");
+        header.card(&format!("TFORM{}", field + 1), Value::Str(tform), "");
+    }
+    header.card(
+        "ZIMAGE",
+        Value::Logical(true),
+        "extension contains compressed image",
+    );
+    header.card(
+        "ZBITPIX",
+        Value::Int(data.bitpix()),
+        "data type of original image",
+    );
+    header.card("ZNAXIS", Value::Int(zimage.naxes.len() as i64), "");
+    for (axis, &n) in zimage.naxes.iter().enumerate() {
+        header.card(&format!("ZNAXIS{}", axis + 1), Value::Int(n as i64), "");
+    }
+    for (axis, &n) in zimage.tile.iter().enumerate() {
+        header.card(&format!("ZTILE{}", axis + 1), Value::Int(n as i64), "");
+    }
+    header.card("ZCMPTYPE", Value::Str("RICE_1"), "compression algorithm");
+    header.card("ZNAME1", Value::Str("BLOCKSIZE"), "");
+    header.card("ZVAL1", Value::Int(zimage.blocksize as i64), "");
+    header.card("ZNAME2", Value::Str("BYTEPIX"), "");
+    header.card("ZVAL2", Value::Int(width.bytepix() as i64), "");
+    if float {
+        let dither = zimage.quantize.dither;
+        header.card(
+            "ZQUANTIZ",
+            Value::Str(dither.keyword()),
+            "quantization method",
+        );
+        if dither != Dither::None {
+            header.card(
+                "ZDITHER0",
+                Value::Int(zimage.quantize.seed as i64),
+                "dithering offset",
+            );
+        }
+    }
+    let blank = match data {
+        ImageData::F32(pixels) if pixels.iter().any(|v| v.is_nan()) => Some(NULL_VALUE),
+        ImageData::F32(_) => None,
+        _ => zimage.blank,
+    };
+    if let Some(blank) = blank {
+        header.card("ZBLANK", Value::Int(blank as i64), "null pixel value");
+    }
+    out.write_all(&header.finish())?;
+
+    let mut table = Vec::with_capacity(rows.len() * row_len);
+    for row in &rows {
+        let mut descriptors = vec![row.compressed];
+        if uncompressed {
+            descriptors.push(row.uncompressed);
+        }
+        for (len, start) in descriptors {
+            if wide {
+                table.extend_from_slice(&(len as u64).to_be_bytes());
+                table.extend_from_slice(&(start as u64).to_be_bytes());
+            } else {
+                table.extend_from_slice(&(len as u32).to_be_bytes());
+                table.extend_from_slice(&(start as u32).to_be_bytes());
+            }
+        }
+        if float {
+            let scale = row.scale.unwrap_or(TileScale {
+                scale: 1.0,
+                zero: 0.0,
+            });
+            table.extend_from_slice(&scale.scale.to_be_bytes());
+            table.extend_from_slice(&scale.zero.to_be_bytes());
+        }
+    }
+    out.write_all(&table)?;
+    out.write_all(&heap)?;
+    let padding = padded(table.len() + heap.len()) - (table.len() + heap.len());
+    out.write_all(&vec![0; padding])?;
+    Ok(())
+}
+
+/// Find the first `ZIMAGE = T` extension in a FITS file and decompress it.
+pub fn read_zimage(file: &[u8]) -> Result<(ZImageHeader, ImageData), FitsError> {
+    let mut pos = 0;
+    while pos < file.len() {
+        let (header, header_len) = Header::parse(&file[pos..])?;
+        let data_start = pos + header_len;
+        let data_len = header.data_len()?;
+        if header.logical("ZIMAGE")? == Some(true) {
+            let data = data_start
+                .checked_add(data_len)
+                .and_then(|end| file.get(data_start..end))
+                .ok_or(FitsError::Truncated)?;
+            return read_zimage_hdu(&header, data);
+        }
+        pos = data_start.saturating_add(padded(data_len));
+    }
+    Err(FitsError::NoCompressedImage)
+}
+
+fn read_zimage_hdu(header: &Header, data: &[u8]) -> Result<(ZImageHeader, ImageData), FitsError> {
+    let zimage = ZImageHeader::from_header(header)?;
+    let table = Table::read(header, data)?;
+    /* every tile needs at least a descriptor's worth of the table */
+    let min_len = zimage.tiles().checked_mul(MIN_ROW_LEN);
+    if table.rows != zimage.tiles() || min_len.is_none_or(|len| len > data.len()) {
+        return Err(FitsError::InvalidKeyword("NAXIS2".to_string()));
+    }
+    let theap = header
+        .size("THEAP")?
+        .unwrap_or(table.row_len.saturating_mul(table.rows));
+    let heap = data
+        .get(theap..)
+        .ok_or_else(|| FitsError::InvalidKeyword("THEAP".to_string()))?;
+
+    let pixels = match header.required_int("ZBITPIX")? {
+        8 => ImageData::U8(zimage.decode_integer(&table, heap)?),
+        16 => ImageData::I16(zimage.decode_integer(&table, heap)?),
+        32 => ImageData::I32(zimage.decode_integer(&table, heap)?),
+        -32 => ImageData::F32(zimage.decode_float(&table, heap)?),
+        bitpix => return Err(FitsError::Unsupported(format!("ZBITPIX {}", bitpix))),
+    };
+    Ok((zimage, pixels))
+}
+
+/// Heap pointers and scaling of one tile, as written to its table row
+#[derive(Default)]
+struct TileRow {
+    /// Length and heap offset of the Rice coded bytes
+    compressed: (usize, usize),
+    /// Number of pixels and heap offset of a tile stored as it is
+    uncompressed: (usize, usize),
+    scale: Option<TileScale>,
+}
+
+/// A column of the binary table
+struct Column {
+    tform: String,
+    /// Byte offset of the field within a row
+    offset: usize,
+}
+
+/// The rows of a compressed image table and the columns this module reads
+struct Table<'a> {
+    data: &'a [u8],
+    rows: usize,
+    row_len: usize,
+    compressed: Column,
+    uncompressed: Option<Column>,
+    zscale: Option<Column>,
+    zzero: Option<Column>,
+    /// `ZSCALE` and `ZZERO` given once as keywords rather than per tile
+    scale: Option<TileScale>,
+}
+
+impl<'a> Table<'a> {
+    fn read(header: &Header, data: &'a [u8]) -> Result<Table<'a>, FitsError> {
+        let tfields = header.required_size("TFIELDS")?;
+        if tfields > MAX_TFIELDS {
+            return Err(FitsError::InvalidKeyword("TFIELDS".to_string()));
+        }
+        let mut columns = Vec::with_capacity(tfields);
+        let mut offset: usize = 0;
+        for field in 1..=tfields {
+            let tform = header.required_str(&format!("TFORM{}", field))?;
+            let name = header
+                .str(&format!("TTYPE{}", field))
+                .unwrap_or("")
+                .to_ascii_uppercase();
+            columns.push((
+                name,
+                Column {
+                    tform: tform.to_string(),
+                    offset,
+                },
+            ));
+            offset = tform_width(tform)
+                .and_then(|width| offset.checked_add(width))
+                .ok_or_else(|| FitsError::InvalidKeyword(format!("TFORM{}", field)))?;
+        }
+        let mut column = |name: &str| {
+            columns
+                .iter()
+                .position(|(column, _)| column == name)
+                .map(|at| columns.swap_remove(at).1)
+        };
+
+        let scale = match (header.float("ZSCALE")?, header.float("ZZERO")?) {
+            (Some(scale), Some(zero)) => Some(TileScale { scale, zero }),
+            _ => None,
+        };
+        Ok(Table {
+            data,
+            rows: header.required_size("NAXIS2")?,
+            row_len: header.required_size("NAXIS1")?,
+            compressed: column("COMPRESSED_DATA")
+                .ok_or_else(|| FitsError::MissingKeyword("COMPRESSED_DATA".to_string()))?,
+            uncompressed: column("UNCOMPRESSED_DATA"),
+            zscale: column("ZSCALE"),
+            zzero: column("ZZERO"),
+            scale,
+        })
+    }
+
+    /// Bytes of a field of `row`
+    fn field(&self, row: usize, column: &Column, len: usize) -> Result<&'a [u8], FitsError> {
+        /* the row length and offsets come from the header */
+        row.checked_mul(self.row_len)
+            .and_then(|at| at.checked_add(column.offset))
+            .and_then(|at| Some(at..at.checked_add(len)?))
+            .and_then(|range| self.data.get(range))
+            .ok_or(FitsError::Truncated)
+    }
+
+    /// Element count and heap offset of a variable length array
+    fn descriptor(
+        &self,
+        row: usize,
+        column: &Column,
+        element: char,
+    ) -> Result<(usize, usize), FitsError> {
+        match descriptor_type(&column.tform) {
+            Some(('P', code)) if code == element => {
+                let bytes = self.field(row, column, 8)?;
+                let len = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+                let start = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+                Ok((len as usize, start as usize))
+            }
+            Some(('Q', code)) if code == element => {
+                let bytes = self.field(row, column, 16)?;
+                let len = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+                let start = u64::from_be_bytes(bytes[8..16].try_into().unwrap());
+                Ok((
+                    usize::try_from(len).unwrap_or(usize::MAX),
+                    usize::try_from(start).unwrap_or(usize::MAX),
+                ))
+            }
+            _ => Err(FitsError::Unsupported(format!(
+                "column format {}",
+                column.tform
+            ))),
+        }
+    }
+
+    /// Rice coded bytes of tile `row`, or `None` if it was stored otherwise
+    fn compressed(&self, row: usize, heap: &'a [u8]) -> Result<Option<&'a [u8]>, FitsError> {
+        let (len, start) = self.descriptor(row, &self.compressed, 'B')?;
+        if len == 0 {
+            return Ok(None);
+        }
+        start
+            .checked_add(len)
+            .and_then(|end| heap.get(start..end))
+            .map(Some)
+            .ok_or(FitsError::BadDescriptor { tile: row })
+    }
+
+    /// Read tile `row` stored as it is in the `UNCOMPRESSED_DATA` column
+    fn uncompressed(&self, row: usize, heap: &[u8], tile: &mut [f32]) -> Result<(), FitsError> {
+        let column = self.uncompressed.as_ref().ok_or_else(|| {
+            FitsError::Unsupported(format!("tile {} without COMPRESSED_DATA", row))
+        })?;
+        let (len, start) = self.descriptor(row, column, 'E')?;
+        if len != tile.len() {
+            return Err(FitsError::BadDescriptor { tile: row });
+        }
+        let bytes = len
+            .checked_mul(4)
+            .and_then(|size| start.checked_add(size))
+            .and_then(|end| heap.get(start..end))
+            .ok_or(FitsError::BadDescriptor { tile: row })?;
+        for (value, bytes) in tile.iter_mut().zip(bytes.chunks_exact(4)) {
+            *value = f32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        Ok(())
+    }
+
+    /// Scaling of quantized tile `row`
+    fn scale(&self, row: usize) -> Result<TileScale, FitsError> {
+        let double = |column: &Option<Column>| -> Result<Option<f64>, FitsError> {
+            match column {
+                Some(column) if tform_code(&column.tform) == Some('D') => {
+                    let bytes = self.field(row, column, 8)?;
+                    Ok(Some(f64::from_be_bytes(bytes.try_into().unwrap())))
+                }
+                Some(column) => Err(FitsError::Unsupported(format!(
+                    "column format {}",
+                    column.tform
+                ))),
+                None => Ok(None),
+            }
+        };
+        match (double(&self.zscale)?, double(&self.zzero)?, self.scale) {
+            (Some(scale), Some(zero), _) => Ok(TileScale { scale, zero }),
+            (_, _, Some(scale)) => Ok(scale),
+            _ => Err(FitsError::MissingKeyword("ZSCALE".to_string())),
+        }
+    }
+}
+
+/// Width tiles of a `ZBITPIX` are coded at when there is no `BYTEPIX`
+fn bitpix_width(bitpix: i64) -> Result<SampleWidth, FitsError> {
+    match bitpix {
+        8 => Ok(SampleWidth::Byte),
+        16 => Ok(SampleWidth::Short),
+        32 | -32 => Ok(SampleWidth::Int),
+        _ => Err(FitsError::Unsupported(format!("ZBITPIX {}", bitpix))),
+    }
+}
+
+/// Most pixels a Rice coded tile of `len` bytes can hold: after the first
+/// value every block of up to `blocksize` pixels costs at least its header
+fn max_tile_pixels(len: usize, width: SampleWidth, blocksize: usize) -> usize {
+    let bits = len.saturating_mul(8).saturating_sub(width.bbits() as usize);
+    (bits / width.fsbits() as usize).saturating_mul(blocksize)
+}
+
+/// Round a length up to a whole number of FITS blocks
+fn padded(len: usize) -> usize {
+    len.div_ceil(BLOCK_LEN).saturating_mul(BLOCK_LEN)
+}
+
+/// Width in bytes of a binary table field with the given `TFORMn`
+fn tform_width(tform: &str) -> Option<usize> {
+    let tform = tform.trim();
+    let code_at = tform.find(|c: char| !c.is_ascii_digit())?;
+    let repeat: usize = match code_at {
+        0 => 1,
+        _ => tform[..code_at].parse().ok()?,
+    };
+    let width: usize = match tform[code_at..].chars().next()?.to_ascii_uppercase() {
+        'L' | 'B' | 'A' => 1,
+        'I' => 2,
+        'J' | 'E' => 4,
+        'K' | 'D' | 'C' | 'P' => 8,
+        'M' | 'Q' => 16,
+        'X' => return Some(repeat.div_ceil(8)),
+        _ => return None,
+    };
+    repeat.checked_mul(width)
+}
+
+/// Data type code of a `TFORMn`, such as `'D'` for `1D`
+fn tform_code(tform: &str) -> Option<char> {
+    tform
+        .trim()
+        .trim_start_matches(|c: char| c.is_ascii_digit())
+        .chars()
+        .next()
+        .map(|c| c.to_ascii_uppercase())
+}
+
+/// Descriptor and element type of a variable length array `TFORMn`, such as
+/// `('P', 'B')` for `1PB(1234)`
+fn descriptor_type(tform: &str) -> Option<(char, char)> {
+    let mut codes = tform
+        .trim()
+        .trim_start_matches(|c: char| c.is_ascii_digit())
+        .chars()
+        .map(|c| c.to_ascii_uppercase());
+    Some((codes.next()?, codes.next()?))
+}
+
+/// Keyword records of one header, with string values unquoted
+struct Header {
+    cards: Vec<(String, String)>,
+}
+
+impl Header {
+    /// Parse the header at the start of `input`, returning it and its length
+    /// in bytes including padding
+    fn parse(input: &[u8]) -> Result<(Header, usize), FitsError> {
+        let mut cards = Vec::new();
+        for (index, card) in input.chunks(CARD_LEN).enumerate() {
+            if card.len() < CARD_LEN {
+                break;
+            }
+            let card = std::str::from_utf8(card)
+                .ok()
+                .filter(|card| card.is_ascii())
+                .ok_or_else(|| FitsError::Unsupported("non-ASCII header record".to_string()))?;
+            let keyword = card[..8].trim_end();
+            if keyword == "END" {
+                return Ok((Header { cards }, padded((index + 1) * CARD_LEN)));
+            }
+            if &card[8..10] == "= " {
+                cards.push((keyword.to_string(), parse_value(&card[10..])));
+            }
+        }
+        Err(FitsError::Truncated)
+    }
+
+    fn str(&self, keyword: &str) -> Option<&str> {
+        self.cards
+            .iter()
+            .find(|(name, _)| name == keyword)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn required_str(&self, keyword: &str) -> Result<&str, FitsError> {
+        self.str(keyword)
+            .ok_or_else(|| FitsError::MissingKeyword(keyword.to_string()))
+    }
+
+    fn int(&self, keyword: &str) -> Result<Option<i64>, FitsError> {
+        self.str(keyword)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| FitsError::InvalidKeyword(keyword.to_string()))
+            })
+            .transpose()
+    }
+
+    fn required_int(&self, keyword: &str) -> Result<i64, FitsError> {
+        self.int(keyword)?
+            .ok_or_else(|| FitsError::MissingKeyword(keyword.to_string()))
+    }
+
+    /// A non-negative integer keyword
+    fn size(&self, keyword: &str) -> Result<Option<usize>, FitsError> {
+        self.int(keyword)?
+            .map(|value| {
+                usize::try_from(value).map_err(|_| FitsError::InvalidKeyword(keyword.to_string()))
+            })
+            .transpose()
+    }
+
+    fn required_size(&self, keyword: &str) -> Result<usize, FitsError> {
+        self.size(keyword)?
+            .ok_or_else(|| FitsError::MissingKeyword(keyword.to_string()))
+    }
+
+    /// A real keyword, which may use a `D` exponent
+    fn float(&self, keyword: &str) -> Result<Option<f64>, FitsError> {
+        self.str(keyword)
+            .map(|value| {
+                value
+                    .replace(['D', 'd'], "E")
+                    .parse()
+                    .map_err(|_| FitsError::InvalidKeyword(keyword.to_string()))
+            })
+            .transpose()
+    }
+
+    fn logical(&self, keyword: &str) -> Result<Option<bool>, FitsError> {
+        match self.str(keyword) {
+            None => Ok(None),
+            Some("T") => Ok(Some(true)),
+            Some("F") => Ok(Some(false)),
+            Some(_) => Err(FitsError::InvalidKeyword(keyword.to_string())),
+        }
+    }
+
+    /// Compression parameter names from `ZNAMEn`, each with the keyword
+    /// holding its value
+    fn parameters(&self) -> impl Iterator<Item = (&str, String)> + '_ {
+        (1..).map_while(|n| Some((self.str(&format!("ZNAME{}", n))?, format!("ZVAL{}", n))))
+    }
+
+    /// Size in bytes of the data unit following the header, without padding
+    fn data_len(&self) -> Result<usize, FitsError> {
+        let naxis = self.required_size("NAXIS")?;
+        if naxis == 0 {
+            return Ok(0);
+        }
+        let bitpix = self.required_int("BITPIX")?;
+        let overflow = || FitsError::InvalidKeyword("NAXIS".to_string());
+
+        let mut elements: usize = 1;
+        for axis in 1..=naxis {
+            let n = self.required_size(&format!("NAXIS{}", axis))?;
+            elements = elements.checked_mul(n).ok_or_else(overflow)?;
+        }
+        let pcount = self.size("PCOUNT")?.unwrap_or(0);
+        let gcount = self.size("GCOUNT")?.unwrap_or(1);
+        elements
+            .checked_add(pcount)
+            .and_then(|n| n.checked_mul(gcount))
+            .and_then(|n| n.checked_mul(bitpix.unsigned_abs() as usize / 8))
+            .ok_or_else(overflow)
+    }
+}
+
+/// Value field of a keyword record: a quoted string with doubled quotes
+/// undone and trailing blanks dropped, or the token before any comment
+fn parse_value(field: &str) -> String {
+    let field = field.trim_start();
+    match field.strip_prefix('\'') {
+        Some(rest) => {
+            let mut value = String::new();
+            let mut chars = rest.chars().peekable();
+            while let Some(c) = chars.next() {
+                if c == '\'' {
+                    if chars.peek() != Some(&'\'') {
+                        break;
+                    }
+                    chars.next();
+                }
+                value.push(c);
+            }
+            value.trim_end().to_string()
+        }
+        None => field.split('/').next().unwrap_or("").trim().to_string(),
+    }
+}
+
+enum Value<'a> {
+    Logical(bool),
+    Int(i64),
+    Str(&'a str),
+}
+
+/// Builds a header of fixed-format keyword records
+#[derive(Default)]
+struct HeaderWriter {
+    bytes: Vec<u8>,
+}
+
+impl HeaderWriter {
+    fn card(&mut self, keyword: &str, value: Value, comment: &str) {
+        /* numbers and logicals end in column 30, strings start in column 11,
+         * comments start in column 32 */
+        let value = match value {
+            Value::Logical(value) => format!("{:>20}", if value { "T" } else { "F" }),
+            Value::Int(value) => format!("{:>20}", value),
+            Value::Str(value) => format!("'{:<8}'", value.replace('\'', "''")),
+        };
+        let mut card = format!("{:<8}= {}", keyword, value);
+        if !comment.is_empty() {
+            card = format!("{:<30} / {}", card, comment);
+        }
+        card.truncate(CARD_LEN);
+        self.bytes
+            .extend_from_slice(format!("{:<80}", card).as_bytes());
+    }
+
+    /// The header with its END record, padded with blanks to whole blocks
+    fn finish(mut self) -> Vec<u8> {
+        self.bytes
+            .extend_from_slice(format!("{:<80}", "END").as_bytes());
+        self.bytes.resize(padded(self.bytes.len()), b' ');
+        self.bytes
+    }
+}
@@ -0,0 +1,181 @@
+//! Self-describing container around a Rice coded stream.
+//!
+//! The raw CFITSIO stream carries no metadata, so the pixel count, block size
+//! and sample width have to be known out of band. A framed stream starts with
+//! a fixed header holding all three, optionally followed after the coded
+//! pixels by a CRC-32 of the coded bytes:
+//!
+//! | offset | size | field                                        |
+//! |--------|------|----------------------------------------------|
+//! | 0      | 4    | magic, `RICE`                                |
+//! | 4      | 1    | format version, currently 1                  |
+//! | 5      | 1    | bytes per pixel: 1, 2, 4 or 8                |
+//! | 6      | 1    | flags, bit 0 set if a checksum follows       |
+//! | 7      | 1    | reserved, 0                                  |
+//! | 8      | 8    | coding block size, big-endian                |
+//! | 16     | 8    | number of pixels, big-endian                 |
+//! | 24     | ...  | Rice coded stream, as written by `encode`    |
+//! | ...    | 4    | CRC-32 of the coded stream, big-endian       |
+//!
+//! Signed and unsigned samples of the same width are coded identically, so
+//! the header only records the width. The block size is at most
+//! [`MAX_BLOCK`], and the pixel count must be one the coded stream can hold.
+
+use std::io::{self, Write};
+
+use crate::{read::DecodeError, sample::SampleWidth};
+
+pub const MAGIC: [u8; 4] = *b"RICE";
+pub const VERSION: u8 = 1;
+/// Size in bytes of the header
+pub const HEADER_LEN: usize = 24;
+/// Size in bytes of the checksum trailer
+pub const CHECKSUM_LEN: usize = 4;
+
+/// Largest coding block size a framed stream may have
+pub const MAX_BLOCK: usize = 1 << 16;
+
+const FLAG_CHECKSUM: u8 = 1;
+
+/// Metadata stored at the start of a framed stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub width: SampleWidth,
+    pub nblock: usize,
+    pub nx: usize,
+    /// A CRC-32 of the coded stream follows it
+    pub checksum: bool,
+}
+
+impl FrameHeader {
+    /// Parse the header at the start of `input`, e.g. to find out which
+    /// sample type to decode a stream into.
+    ///
+    /// `input` must be the whole stream: every coding block costs at least
+    /// the bits of its header, so a pixel count needing more blocks than the
+    /// rest of `input` has room for is rejected.
+    pub fn read(input: &[u8]) -> Result<FrameHeader, DecodeError> {
+        let header = match input.get(..HEADER_LEN) {
+            Some(header) => header,
+            None => {
+                return Err(DecodeError::EndOfBuffer {
+                    pixel: 0,
+                    offset: input.len(),
+                })
+            }
+        };
+
+        if header[0..4] != MAGIC || header[4] != VERSION {
+            return Err(DecodeError::InvalidHeader);
+        }
+        let width =
+            SampleWidth::from_bytepix(header[5] as usize).ok_or(DecodeError::InvalidHeader)?;
+        if header[6] & !FLAG_CHECKSUM != 0 || header[7] != 0 {
+            return Err(DecodeError::InvalidHeader);
+        }
+
+        let field = |at: usize| {
+            let bytes: [u8; 8] = header[at..at + 8].try_into().unwrap();
+            usize::try_from(u64::from_be_bytes(bytes)).map_err(|_| DecodeError::InvalidHeader)
+        };
+        /* an all zero block costs only its header, so a large block size
+         * would serve to claim a huge image from a few bytes */
+        let nblock = field(8)?;
+        if nblock == 0 || nblock > MAX_BLOCK {
+            return Err(DecodeError::InvalidHeader);
+        }
+        let nx = field(16)?;
+        let payload_bits = (input.len() - HEADER_LEN).saturating_mul(8);
+        let max_nx = (payload_bits / width.fsbits() as usize).saturating_mul(nblock);
+        if nx > max_nx {
+            return Err(DecodeError::InvalidHeader);
+        }
+
+        Ok(FrameHeader {
+            width,
+            nblock,
+            nx,
+            checksum: header[6] & FLAG_CHECKSUM != 0,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4] = VERSION;
+        header[5] = self.width.bytepix() as u8;
+        header[6] = if self.checksum { FLAG_CHECKSUM } else { 0 };
+        header[8..16].copy_from_slice(&(self.nblock as u64).to_be_bytes());
+        header[16..24].copy_from_slice(&(self.nx as u64).to_be_bytes());
+        header
+    }
+}
+
+/// CRC-32 lookup table for the reflected polynomial 0xedb88320 (as used by
+/// zlib and PNG)
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+/// Running CRC-32 of a byte stream
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub(crate) fn new() -> Self {
+        Crc32(0xffffffff)
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = CRC_TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub(crate) fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+/// Writer that keeps a CRC-32 of everything written through it
+pub(crate) struct ChecksumWriter<W: Write> {
+    pub(crate) inner: W,
+    pub(crate) crc: Crc32,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        ChecksumWriter {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
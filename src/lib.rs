@@ -1,11 +1,25 @@
+#![deny(clippy::undocumented_unsafe_blocks)]
+
 fn log_noop(_msg: &str) {
     // noop
 }
 
+pub mod fits;
+pub mod frame;
+pub mod noise;
+pub mod nulls;
+pub mod quantize;
 pub mod read;
+pub mod sample;
+mod simd;
+pub mod stats;
 pub mod write;
 
-const EOF: i32 = -1;
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct DataLong {
+    pub d: Vec<i64>,
+}
 
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
@@ -29,6 +43,8 @@ pub struct DataByte {
 mod tests {
     use std::vec;
 
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
@@ -60,7 +76,6 @@ mod tests {
         let decoder = read::RCDecoder::new();
         let mut new_inarray = vec![0; 32];
         let _result = decoder.decode(&outarray, 32, bs, &mut new_inarray);
-        let new_inarray: Vec<i32> = new_inarray.iter().map(|&x| x as i32).collect();
         assert_eq!(new_inarray.len(), 32);
         assert_eq!(inarray.to_vec(), new_inarray);
     }
@@ -80,7 +95,6 @@ mod tests {
         let decoder = read::RCDecoder::new();
         let mut new_inarray = vec![0; 32];
         let _result = decoder.decode_short(&outarray, 32, bs, &mut new_inarray);
-        let new_inarray: Vec<i16> = new_inarray.iter().map(|&x| x as i16).collect();
         assert_eq!(new_inarray.len(), 32);
         assert_eq!(inarray.to_vec(), new_inarray);
     }
@@ -100,14 +114,1470 @@ mod tests {
         let decoder = read::RCDecoder::new();
         let mut new_inarray = vec![0; 32];
         let _result = decoder.decode_byte(&outarray, 32, bs, &mut new_inarray);
-        let new_inarray: Vec<i8> = new_inarray.iter().map(|&x| x as i8).collect();
         assert_eq!(new_inarray.len(), 32);
         assert_eq!(inarray.to_vec(), new_inarray);
     }
 
-    // This fails for unknown reasons
-    // #[test]
-    fn _bad_encoding() {
+    #[test]
+    fn decode_unsigned_works() {
+        let bs = 16;
+        let inarray: Vec<u32> = (0..40).map(|x| u32::MAX - x * 1000).collect();
+        let mut outarray = Vec::new();
+        let mut encoder = write::RCEncoder::new(&mut outarray);
+        encoder.encode_unsigned(&inarray, 40, bs).unwrap();
+        let decoder = read::RCDecoder::new();
+        let mut new_inarray = vec![0; 40];
+        decoder
+            .decode_unsigned(&outarray, 40, bs, &mut new_inarray)
+            .unwrap();
+        assert_eq!(inarray, new_inarray);
+
+        let inarray: Vec<u16> = (0..40).map(|x| u16::MAX - x * 1000).collect();
+        let mut outarray = Vec::new();
+        let mut encoder = write::RCEncoder::new(&mut outarray);
+        encoder.encode_ushort(&inarray, 40, bs).unwrap();
+        let mut new_inarray = vec![0; 40];
+        decoder
+            .decode_ushort(&outarray, 40, bs, &mut new_inarray)
+            .unwrap();
+        assert_eq!(inarray, new_inarray);
+
+        let inarray: Vec<u8> = (0..40).map(|x| 255 - x * 6).collect();
+        let mut outarray = Vec::new();
+        let mut encoder = write::RCEncoder::new(&mut outarray);
+        encoder.encode_ubyte(&inarray, 40, bs).unwrap();
+        let mut new_inarray = vec![0; 40];
+        decoder
+            .decode_ubyte(&outarray, 40, bs, &mut new_inarray)
+            .unwrap();
+        assert_eq!(inarray, new_inarray);
+    }
+
+    #[test]
+    fn encode_unsigned_matches_signed() {
+        let signed: Vec<i32> = (0..40).map(|x| x * 1000 - 20000).collect();
+        let unsigned: Vec<u32> = signed.iter().map(|&x| x as u32).collect();
+
+        let mut signed_out = Vec::new();
+        write::RCEncoder::new(&mut signed_out)
+            .encode(&signed, 40, 16)
+            .unwrap();
+        let mut unsigned_out = Vec::new();
+        write::RCEncoder::new(&mut unsigned_out)
+            .encode_unsigned(&unsigned, 40, 16)
+            .unwrap();
+        assert_eq!(signed_out, unsigned_out);
+    }
+
+    fn roundtrip<T: sample::RiceSample + PartialEq + std::fmt::Debug>(input: &[T], bs: usize) {
+        let mut outarray = Vec::new();
+        let mut encoder = write::RCEncoder::new(&mut outarray);
+        let len = encoder.encode(input, input.len(), bs).unwrap();
+        assert_eq!(encoder.compressed_len(input, bs).unwrap(), len);
+        assert!(outarray.len() <= write::max_compressed_size(input.len(), bs, T::WIDTH));
+
+        let decoder = read::RCDecoder::new();
+        assert_eq!(
+            decoder
+                .validate(&outarray, input.len(), bs, T::WIDTH)
+                .unwrap(),
+            0
+        );
+        let mut new_inarray = vec![T::default(); input.len()];
+        decoder
+            .decode(&outarray, input.len(), bs, &mut new_inarray)
+            .unwrap();
+        assert_eq!(input, &new_inarray[..]);
+
+        /* the optimal choice is never longer and reads back the same */
+        let mut optimal = Vec::new();
+        let mut encoder = write::RCEncoder::new(&mut optimal);
+        encoder.set_fs_strategy(write::FsStrategy::Optimal);
+        let len = encoder.encode(input, input.len(), bs).unwrap();
+        assert_eq!(encoder.compressed_len(input, bs).unwrap(), len);
+        assert!(optimal.len() <= outarray.len());
+
+        let mut new_inarray = vec![T::default(); input.len()];
+        decoder
+            .decode(&optimal, input.len(), bs, &mut new_inarray)
+            .unwrap();
+        assert_eq!(input, &new_inarray[..]);
+    }
+
+    #[test]
+    fn optimal_fs_beats_estimate_on_skewed_blocks() {
+        /* flat noise with sparse cosmic ray hits skews the block means */
+        let mut seed: u32 = 12345;
+        let inarray: Vec<i32> = (0..32000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let r = (seed >> 16) as i32;
+                if r % 16 == 0 {
+                    1000 + (r & 0xfff) * 16
+                } else {
+                    1000 + (r & 7)
+                }
+            })
+            .collect();
+
+        let mut estimated = Vec::new();
+        write::RCEncoder::new(&mut estimated)
+            .encode(&inarray, inarray.len(), 32)
+            .unwrap();
+        let mut optimal = Vec::new();
+        let mut encoder = write::RCEncoder::new(&mut optimal);
+        encoder.set_fs_strategy(write::FsStrategy::Optimal);
+        encoder.encode(&inarray, inarray.len(), 32).unwrap();
+        assert!(
+            optimal.len() < estimated.len(),
+            "{} >= {}",
+            optimal.len(),
+            estimated.len()
+        );
+
+        assert_eq!(write::optimal_fs(&[0; 32], 25, 32), 0);
+        assert_eq!(write::optimal_fs(&[u32::MAX as u64; 32], 25, 32), 25);
+        assert_eq!(write::optimal_fs(&[1; 32], 25, 32), 0);
+        assert_eq!(write::optimal_fs(&[7; 32], 25, 32), 2);
+    }
+
+    #[test]
+    fn generic_roundtrip_all_widths() {
+        let base: Vec<i64> = (0..100).map(|x| (x * 37 % 23) * x - 500).collect();
+        for bs in [1, 7, 16, 32, 100] {
+            roundtrip(&base.iter().map(|&x| x as i8).collect::<Vec<_>>(), bs);
+            roundtrip(&base.iter().map(|&x| x as u8).collect::<Vec<_>>(), bs);
+            roundtrip(&base.iter().map(|&x| x as i16).collect::<Vec<_>>(), bs);
+            roundtrip(&base.iter().map(|&x| x as u16).collect::<Vec<_>>(), bs);
+            roundtrip(&base.iter().map(|&x| x as i32).collect::<Vec<_>>(), bs);
+            roundtrip(&base.iter().map(|&x| x as u32).collect::<Vec<_>>(), bs);
+            roundtrip(
+                &base.iter().map(|&x| x * 1_000_000_007).collect::<Vec<_>>(),
+                bs,
+            );
+            roundtrip(&base.iter().map(|&x| x as u64).collect::<Vec<_>>(), bs);
+        }
+    }
+
+    #[test]
+    fn decode_long_works() {
+        let bs = 16;
+        let inarray: Vec<i64> = (0..40)
+            .map(|x| 0x1234_5678_9abc_def0 + x * x * (1 << 40))
+            .collect();
+
+        let mut outarray = Vec::new();
+        let mut encoder = write::RCEncoder::new(&mut outarray);
+        encoder.encode_long(&inarray, 40, bs).unwrap();
+        assert_eq!(outarray[..8], 0x1234_5678_9abc_def0i64.to_be_bytes());
+
+        let decoder = read::RCDecoder::new();
+        let mut new_inarray = vec![0; 40];
+        decoder
+            .decode_long(&outarray, 40, bs, &mut new_inarray)
+            .unwrap();
+        assert_eq!(inarray, new_inarray);
+
+        // directly coded block of 64-bit values
+        let inarray: Vec<u64> = (0..40)
+            .map(|x: u64| x.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+            .collect();
+        let mut outarray = Vec::new();
+        let mut encoder = write::RCEncoder::new(&mut outarray);
+        encoder.encode_ulong(&inarray, 40, bs).unwrap();
+        let mut new_inarray = vec![0; 40];
+        decoder
+            .decode_ulong(&outarray, 40, bs, &mut new_inarray)
+            .unwrap();
+        assert_eq!(inarray, new_inarray);
+    }
+
+    #[test]
+    fn decode_truncated_is_error() {
+        let bs = 16;
+        let inarray: Vec<i32> = (0..100).map(|x| x * x * 1000 - 70000).collect();
+
+        let mut outarray = Vec::new();
+        let mut encoder = write::RCEncoder::new(&mut outarray);
+        encoder.encode(&inarray, 100, bs).unwrap();
+
+        let decoder = read::RCDecoder::new();
+        let mut new_inarray = vec![0; 100];
+        for len in 0..outarray.len() {
+            let result = decoder.decode(&outarray[..len], 100, bs, &mut new_inarray);
+            match result {
+                Err(read::DecodeError::EndOfBuffer { offset, .. }) => assert_eq!(offset, len),
+                Err(read::DecodeError::NotProperlyAllocated) => assert!(len < 4),
+                other => panic!("unexpected result {:?} for length {}", other, len),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_garbage_does_not_panic() {
+        let decoder = read::RCDecoder::new();
+        let mut seed: u32 = 12345;
+        for len in 0..200 {
+            let garbage: Vec<u8> = (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                })
+                .collect();
+            let mut out_int = vec![0; 64];
+            let mut out_short = vec![0; 64];
+            let mut out_byte = vec![0; 64];
+            let _ = decoder.decode(&garbage, 64, 8, &mut out_int);
+            let _ = decoder.decode_short(&garbage, 64, 8, &mut out_short);
+            let _ = decoder.decode_byte(&garbage, 64, 8, &mut out_byte);
+        }
+
+        let ones = vec![0xffu8; 1024];
+        let mut out_int = vec![0; 64];
+        assert!(matches!(
+            decoder.decode(&ones, 64, 8, &mut out_int),
+            Err(read::DecodeError::CorruptStream { pixel: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn decode_wrong_output_size_is_error() {
+        let decoder = read::RCDecoder::new();
+        let mut out = vec![0; 10];
+        assert!(matches!(
+            decoder.decode(&[0, 0, 0, 0, 0], 11, 8, &mut out),
+            Err(read::DecodeError::NotProperlyAllocated)
+        ));
+        assert!(matches!(
+            decoder.decode(&[0, 0, 0, 0, 0], 10, 0, &mut out),
+            Err(read::DecodeError::ZeroSizeInput)
+        ));
+    }
+
+    #[test]
+    fn encode_full_buffer_is_error() {
+        let inarray: Vec<i32> = (0..100).map(|x| x * x * 1000 - 70000).collect();
+
+        let mut full = Vec::new();
+        let len = write::RCEncoder::new(&mut full)
+            .encode(&inarray, 100, 16)
+            .unwrap();
+
+        let mut outarray = [0u8; 16];
+        let mut encoder = write::RCEncoder::new(&mut outarray[..]);
+        assert!(matches!(
+            encoder.encode(&inarray, 100, 16),
+            Err(write::EncodeError::EndOfBuffer { needed }) if needed == len
+        ));
+    }
+
+    #[test]
+    fn compressed_len_works() {
+        let encoder = write::RCEncoder::new(std::io::sink());
+        /* raw first value plus one all-zero block header */
+        assert_eq!(encoder.compressed_len(&[5i32; 100], 100).unwrap(), 5);
+        assert_eq!(encoder.compressed_len(&[5i8; 100], 100).unwrap(), 2);
+        assert!(matches!(
+            encoder.compressed_len::<i16>(&[], 32),
+            Err(write::EncodeError::ZeroSizeInput)
+        ));
+        assert!(matches!(
+            encoder.compressed_len(&[1i16], 0),
+            Err(write::EncodeError::ZeroSizeInput)
+        ));
+    }
+
+    #[test]
+    fn block_stats_match_between_encoder_and_decoder() {
+        /* flat run, smooth ramp and noise so all three block kinds appear */
+        let mut seed: u32 = 1;
+        let inarray: Vec<i16> = (0..1000)
+            .map(|x| match x {
+                0..=299 => 42,
+                300..=599 => x as i16,
+                _ => {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    (seed >> 16) as i16
+                }
+            })
+            .collect();
+
+        for strategy in [write::FsStrategy::Estimate, write::FsStrategy::Optimal] {
+            let mut outarray = Vec::new();
+            let mut encoder = write::RCEncoder::new(&mut outarray);
+            encoder.set_fs_strategy(strategy);
+            let (len, stats) = encoder.encode_with_stats(&inarray, 1000, 32).unwrap();
+            assert_eq!(stats.len(), 1000usize.div_ceil(32));
+            assert_eq!(stats.last().unwrap().pixels, 1000 % 32);
+            for kind in [
+                stats::BlockKind::Zero,
+                stats::BlockKind::Rice,
+                stats::BlockKind::Raw,
+            ] {
+                assert!(stats.iter().any(|block| block.kind == kind));
+            }
+            let bits: u64 = stats.iter().map(|block| block.bits).sum();
+            assert_eq!((bits + 16).div_ceil(8) as usize, len);
+
+            let mut decoded = vec![0i16; 1000];
+            let decoded_stats = read::RCDecoder::new()
+                .decode_with_stats(&outarray, 1000, 32, &mut decoded)
+                .unwrap();
+            assert_eq!(decoded, inarray);
+            assert_eq!(decoded_stats, stats);
+        }
+    }
+
+    #[test]
+    fn encode_auto_picks_shortest_block_size() {
+        let inarray: Vec<i32> = (0..4000).map(|x| (x % 97) * (x % 13) * 1000 - 7).collect();
+        let candidates = [8, 16, 32, 64, 128, 0];
+
+        let mut outarray = Vec::new();
+        let mut encoder = write::RCEncoder::new(&mut outarray);
+        let lens: Vec<usize> = candidates[..5]
+            .iter()
+            .map(|&bs| encoder.compressed_len(&inarray, bs).unwrap())
+            .collect();
+        let shortest = *lens.iter().min().unwrap();
+        let expected = candidates[lens.iter().position(|&len| len == shortest).unwrap()];
+        assert_eq!(
+            encoder.choose_block_size(&inarray, &candidates).unwrap(),
+            expected
+        );
+
+        let (len, bs) = encoder.encode_auto(&inarray, &candidates).unwrap();
+        assert_eq!((len, bs), (shortest, expected));
+
+        let mut new_inarray = vec![0; inarray.len()];
+        read::RCDecoder::new()
+            .decode(&outarray, inarray.len(), bs, &mut new_inarray)
+            .unwrap();
+        assert_eq!(new_inarray, inarray);
+
+        let encoder = write::RCEncoder::new(std::io::sink());
+        assert!(matches!(
+            encoder.choose_block_size(&inarray, &[0]),
+            Err(write::EncodeError::ZeroSizeInput)
+        ));
+    }
+
+    #[test]
+    fn encode_into_works() {
+        let inarray: Vec<i16> = (0..100).map(|x| x * x - 700).collect();
+
+        let mut full = Vec::new();
+        let len = write::RCEncoder::new(&mut full)
+            .encode(&inarray, 100, 32)
+            .unwrap();
+
+        let mut outarray = vec![0u8; len];
+        assert_eq!(
+            write::encode_into(&inarray, 100, 32, &mut outarray).unwrap(),
+            len
+        );
+        assert_eq!(outarray, full);
+
+        let mut outarray = vec![0u8; len - 1];
+        assert!(matches!(
+            write::encode_into(&inarray, 100, 32, &mut outarray),
+            Err(write::EncodeError::EndOfBuffer { needed }) if needed == len
+        ));
+
+        let size = write::max_compressed_size(100, 32, sample::SampleWidth::Short);
+        let mut outarray = vec![0u8; size];
+        assert_eq!(
+            write::encode_into(&inarray, 100, 32, &mut outarray).unwrap(),
+            len
+        );
+    }
+
+    #[test]
+    fn encode_writes_in_chunks() {
+        struct CountingWriter(Vec<u8>, usize);
+        impl std::io::Write for CountingWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.1 += 1;
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let inarray: Vec<i32> = (0..100_000).map(|x| (x * 7919) % 100_003).collect();
+        let mut full = Vec::new();
+        let len = write::RCEncoder::new(&mut full)
+            .encode(&inarray, inarray.len(), 32)
+            .unwrap();
+
+        let mut counting = CountingWriter(Vec::new(), 0);
+        write::RCEncoder::new(&mut counting)
+            .encode(&inarray, inarray.len(), 32)
+            .unwrap();
+        assert_eq!(counting.0, full);
+        assert!(counting.1 <= len / 4096 + 1, "{} writes", counting.1);
+    }
+
+    #[test]
+    fn encode_io_error_is_propagated() {
+        struct BrokenPipe;
+        impl std::io::Write for BrokenPipe {
+            fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let inarray: [i16; 4] = [1, 2, 3, 4];
+        let mut encoder = write::RCEncoder::new(BrokenPipe);
+        match encoder.encode_short(&inarray, 4, 16) {
+            Err(write::EncodeError::Io(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    fn stream_matches_encode<T: sample::RiceSample>(input: &[T], bs: usize, chunk: usize) {
+        let mut full = Vec::new();
+        let len = write::RCEncoder::new(&mut full)
+            .encode(input, input.len(), bs)
+            .unwrap();
+
+        let mut stream = write::RCStreamEncoder::new(Vec::new(), bs);
+        for pixels in input.chunks(chunk) {
+            stream.push(pixels).unwrap();
+        }
+        let (streamed_len, streamed) = stream.finish().unwrap();
+        assert_eq!(streamed_len, len);
+        assert_eq!(streamed, full);
+    }
+
+    #[test]
+    fn stream_encode_works() {
+        let inarray: Vec<i32> = (0..1000).map(|x| (x % 97) * (x % 13) * 1000 - 7).collect();
+        for chunk in [1, 5, 16, 17, 100, 1000] {
+            stream_matches_encode(&inarray, 16, chunk);
+            stream_matches_encode(&inarray, 32, chunk);
+        }
+
+        let mut stream = write::RCStreamEncoder::<_, i16>::new(Vec::new(), 32);
+        stream.push(&[]).unwrap();
+        assert!(matches!(
+            stream.finish(),
+            Err(write::EncodeError::ZeroSizeInput)
+        ));
+
+        let mut stream = write::RCStreamEncoder::new(Vec::new(), 0);
+        assert!(matches!(
+            stream.push(&[1i8]),
+            Err(write::EncodeError::ZeroSizeInput)
+        ));
+
+        /* a failed push cannot be retried */
+        let mut sink = [0u8; 8];
+        let mut stream = write::RCStreamEncoder::new(&mut sink[..], 16);
+        let noisy: Vec<i32> = (0..20000).map(|x| (x * 7919) % 65536).collect();
+        assert!(stream.push(&noisy).is_err());
+        assert!(matches!(
+            stream.push(&inarray[..16]),
+            Err(write::EncodeError::Poisoned)
+        ));
+        assert!(matches!(stream.finish(), Err(write::EncodeError::Poisoned)));
+    }
+
+    fn stream_decode_matches_decode<T>(input: &[T], bs: usize, chunk: usize)
+    where
+        T: sample::RiceSample + PartialEq + std::fmt::Debug,
+    {
+        let mut encoded = Vec::new();
+        write::RCEncoder::new(&mut encoded)
+            .encode(input, input.len(), bs)
+            .unwrap();
+
+        let mut decoder = read::RCStreamDecoder::new(&encoded[..], input.len(), bs);
+        let mut decoded = Vec::new();
+        let mut pixels = vec![T::default(); chunk];
+        loop {
+            let n = decoder.read_pixels(&mut pixels).unwrap();
+            if n == 0 {
+                break;
+            }
+            decoded.extend_from_slice(&pixels[..n]);
+        }
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn stream_decode_works() {
+        let inarray: Vec<i32> = (0..1000).map(|x| (x % 97) * (x % 13) * 1000 - 7).collect();
+        for chunk in [1, 5, 16, 17, 100, 1000, 2000] {
+            stream_decode_matches_decode(&inarray, 16, chunk);
+            stream_decode_matches_decode(&inarray, 32, chunk);
+        }
+
+        let mut encoded = Vec::new();
+        write::RCEncoder::new(&mut encoded)
+            .encode(&inarray, 1000, 32)
+            .unwrap();
+        let decoder = read::RCStreamDecoder::<_, i32>::new(&encoded[..], 1000, 32);
+        assert_eq!(decoder.size_hint(), (0, Some(1000)));
+        let decoded: Result<Vec<i32>, _> = decoder.collect();
+        assert_eq!(decoded.unwrap(), inarray);
+
+        let mut decoder = read::RCStreamDecoder::<_, i32>::new(&encoded[..50], 1000, 32);
+        assert!(matches!(
+            decoder.find_map(Result::err),
+            Some(read::DecodeError::EndOfBuffer { offset: 50, .. })
+        ));
+        assert!(decoder.next().is_none());
+    }
+
+    #[test]
+    fn stream_decode_short_reads_work() {
+        /* hands out one byte per read, so every refill takes the tail path */
+        struct Trickle<'a>(&'a [u8]);
+        impl std::io::Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(self.0.len()).min(1);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let inarray: Vec<i64> = (0..1000)
+            .map(|x| (x % 97) * (x % 13) * (1 << 40) - 7)
+            .collect();
+        let mut encoded = Vec::new();
+        write::RCEncoder::new(&mut encoded)
+            .encode(&inarray, 1000, 32)
+            .unwrap();
+
+        let decoder = read::RCStreamDecoder::<_, i64>::new(Trickle(&encoded), 1000, 32);
+        let decoded: Result<Vec<i64>, _> = decoder.collect();
+        assert_eq!(decoded.unwrap(), inarray);
+
+        /* a read interrupted by a signal part way through a block is retried */
+        struct Interrupted<'a> {
+            inner: Trickle<'a>,
+            reads: usize,
+        }
+        impl std::io::Read for Interrupted<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.reads += 1;
+                if self.reads == 100 {
+                    return Err(std::io::ErrorKind::Interrupted.into());
+                }
+                self.inner.read(buf)
+            }
+        }
+        let reader = Interrupted {
+            inner: Trickle(&encoded),
+            reads: 0,
+        };
+        let mut decoder = read::RCStreamDecoder::<_, i64>::new(reader, 1000, 32);
+        let mut decoded = vec![0; 1000];
+        assert_eq!(decoder.read_pixels(&mut decoded).unwrap(), 1000);
+        assert_eq!(decoded, inarray);
+        assert!(decoder.into_inner().reads > 100);
+    }
+
+    #[test]
+    fn stream_decode_io_error_is_propagated() {
+        struct BrokenPipe;
+        impl std::io::Read for BrokenPipe {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+        }
+
+        let mut decoder = read::RCStreamDecoder::<_, i16>::new(BrokenPipe, 10, 16);
+        match decoder.read_pixels(&mut [0; 10]) {
+            Err(read::DecodeError::Io(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(matches!(
+            decoder.read_pixels(&mut [0; 10]),
+            Err(read::DecodeError::Poisoned)
+        ));
+        assert!(decoder.next().is_none());
+
+        /* the blocks decoded before an error are handed out first */
+        let inarray: Vec<i32> = (0..1000).map(|x| (x % 97) * (x % 13) * 1000 - 7).collect();
+        let mut encoded = Vec::new();
+        write::RCEncoder::new(&mut encoded)
+            .encode(&inarray, 1000, 32)
+            .unwrap();
+        let mut decoder = read::RCStreamDecoder::<_, i32>::new(&encoded[..200], 1000, 32);
+        let mut decoded = vec![0; 1000];
+        let n = decoder.read_pixels(&mut decoded).unwrap();
+        assert!(n > 0 && n % 32 == 0);
+        assert_eq!(decoded[..n], inarray[..n]);
+        assert_eq!(decoder.size_hint(), (0, Some(1)));
+        assert!(matches!(
+            decoder.read_pixels(&mut decoded),
+            Err(read::DecodeError::EndOfBuffer { offset: 200, .. })
+        ));
+        assert!(matches!(
+            decoder.read_pixels(&mut decoded),
+            Err(read::DecodeError::Poisoned)
+        ));
+    }
+
+    #[test]
+    fn framed_roundtrip_works() {
+        let inarray: Vec<u16> = (0..1000).map(|x| (x % 97) * (x % 13) * 10).collect();
+        let decoder = read::RCDecoder::new();
+
+        for checksum in [false, true] {
+            let mut framed = Vec::new();
+            let len = write::RCEncoder::new(&mut framed)
+                .encode_framed(&inarray, 32, checksum)
+                .unwrap();
+            assert_eq!(len, framed.len());
+
+            let header = frame::FrameHeader::read(&framed).unwrap();
+            assert_eq!(
+                header,
+                frame::FrameHeader {
+                    width: sample::SampleWidth::Short,
+                    nblock: 32,
+                    nx: 1000,
+                    checksum,
+                }
+            );
+
+            /* the payload is the raw stream */
+            let mut raw = Vec::new();
+            write::RCEncoder::new(&mut raw)
+                .encode(&inarray, 1000, 32)
+                .unwrap();
+            assert_eq!(
+                &framed[frame::HEADER_LEN..frame::HEADER_LEN + raw.len()],
+                raw
+            );
+
+            assert_eq!(decoder.decode_framed::<u16>(&framed).unwrap(), inarray);
+            assert_eq!(
+                decoder.decode_framed::<i16>(&framed).unwrap(),
+                inarray.iter().map(|&x| x as i16).collect::<Vec<_>>()
+            );
+            assert!(matches!(
+                decoder.decode_framed::<i32>(&framed),
+                Err(read::DecodeError::WrongSampleWidth {
+                    expected: sample::SampleWidth::Int,
+                    found: sample::SampleWidth::Short
+                })
+            ));
+
+            let mut outarray = vec![0u8; len - 1];
+            assert!(matches!(
+                write::RCEncoder::new(&mut outarray[..]).encode_framed(&inarray, 32, checksum),
+                Err(write::EncodeError::EndOfBuffer { needed }) if needed == len
+            ));
+        }
+    }
+
+    fn fits_roundtrip(zimage: &fits::ZImageHeader, data: fits::ImageData) -> Vec<u8> {
+        let mut file = Vec::new();
+        fits::write_primary(&mut file).unwrap();
+        fits::write_zimage(&mut file, zimage, &data).unwrap();
+        assert_eq!(file.len() % fits::BLOCK_LEN, 0);
+
+        let (header, decoded) = fits::read_zimage(&file).unwrap();
+        assert!(header.bytepix.is_some());
+        assert!(zimage
+            .bytepix
+            .is_none_or(|width| header.bytepix == Some(width)));
+        let header = fits::ZImageHeader {
+            bytepix: zimage.bytepix,
+            ..header
+        };
+        assert_eq!(&header, zimage);
+        assert_eq!(decoded, data);
+        file
+    }
+
+    #[test]
+    fn fits_roundtrip_works() {
+        /* tiles that do not divide the image leave short edge tiles */
+        let mut zimage = fits::ZImageHeader::new(vec![100, 37]);
+        zimage.tile = vec![32, 8];
+        zimage.blocksize = 16;
+        let pixels: Vec<i16> = (0..3700)
+            .map(|x| ((x % 100) * (x / 100) - 900) as i16)
+            .collect();
+        let file = fits_roundtrip(&zimage, fits::ImageData::I16(pixels.clone()));
+
+        /* the first row of the table points at the first tile in the heap */
+        let header_end = 2 * fits::BLOCK_LEN;
+        let tiles = zimage.tiles();
+        assert_eq!(tiles, 20);
+        let len = u32::from_be_bytes(file[header_end..header_end + 4].try_into().unwrap());
+        let offset = u32::from_be_bytes(file[header_end + 4..header_end + 8].try_into().unwrap());
+        assert_eq!(offset, 0);
+        let tile: Vec<i16> = (0..8)
+            .flat_map(|row| pixels[row * 100..row * 100 + 32].to_vec())
+            .collect();
+        let mut expected = Vec::new();
+        write::RCEncoder::new(&mut expected)
+            .encode(&tile, 256, 16)
+            .unwrap();
+        let heap = header_end + tiles * 8;
+        assert_eq!(&file[heap..heap + len as usize], &expected[..]);
+
+        let pixels: Vec<u8> = (0..4 * 5 * 6).map(|x| (x * 7 % 256) as u8).collect();
+        fits_roundtrip(
+            &fits::ZImageHeader::new(vec![4, 5, 6]),
+            fits::ImageData::U8(pixels),
+        );
+
+        let mut zimage = fits::ZImageHeader::new(vec![1000]);
+        zimage.tile = vec![300];
+        let pixels: Vec<i32> = (0..1000).map(|x| (x % 97) * (x % 13) * 1000 - 7).collect();
+        fits_roundtrip(&zimage, fits::ImageData::I32(pixels));
+    }
+
+    #[test]
+    fn narrowest_width_works() {
+        use sample::SampleWidth;
+        assert_eq!(SampleWidth::narrowest::<i32>(&[]), SampleWidth::Byte);
+        assert_eq!(SampleWidth::narrowest(&[0i32, 255]), SampleWidth::Byte);
+        /* bytes widen back as unsigned, so negative values need 16 bits */
+        assert_eq!(SampleWidth::narrowest(&[0i32, -1]), SampleWidth::Short);
+        assert_eq!(
+            SampleWidth::narrowest(&[-32768i32, 32767]),
+            SampleWidth::Short
+        );
+        assert_eq!(SampleWidth::narrowest(&[40000i32]), SampleWidth::Int);
+        assert_eq!(SampleWidth::narrowest(&[60000u16]), SampleWidth::Short);
+        assert_eq!(SampleWidth::narrowest(&[-1i8]), SampleWidth::Byte);
+        assert_eq!(SampleWidth::narrowest(&[1i64 << 40]), SampleWidth::Long);
+
+        let inarray: Vec<i32> = (0..1000).map(|x| (x * 37) % 200).collect();
+        let mut full = Vec::new();
+        write::RCEncoder::new(&mut full)
+            .encode(&inarray, 1000, 32)
+            .unwrap();
+        let mut narrow = Vec::new();
+        let (len, width) = write::RCEncoder::new(&mut narrow)
+            .encode_narrowest(&inarray, 32)
+            .unwrap();
+        assert_eq!(width, SampleWidth::Byte);
+        assert_eq!(len, narrow.len());
+        assert!(narrow.len() < full.len());
+
+        let decoder = read::RCDecoder::new();
+        let mut new_inarray = vec![0i32; 1000];
+        decoder
+            .decode_as(&narrow, 1000, 32, width, &mut new_inarray)
+            .unwrap();
+        assert_eq!(new_inarray, inarray);
+
+        /* coding wider than the samples works too */
+        let inarray: Vec<i16> = (0..100).map(|x| x * 300 - 15000).collect();
+        let mut wide = Vec::new();
+        write::RCEncoder::new(&mut wide)
+            .encode_as(&inarray, 100, 16, SampleWidth::Int)
+            .unwrap();
+        let mut new_inarray = vec![0i16; 100];
+        decoder
+            .decode_as(&wide, 100, 16, SampleWidth::Int, &mut new_inarray)
+            .unwrap();
+        assert_eq!(new_inarray, inarray);
+    }
+
+    #[test]
+    fn fits_picks_narrowest_bytepix() {
+        let pixels: Vec<i32> = (0..400).map(|x| x % 200).collect();
+        let file = fits_roundtrip(
+            &fits::ZImageHeader::new(vec![20, 20]),
+            fits::ImageData::I32(pixels.clone()),
+        );
+        let (header, _) = fits::read_zimage(&file).unwrap();
+        assert_eq!(header.bytepix, Some(sample::SampleWidth::Byte));
+        assert!(file
+            .chunks(80)
+            .any(|card| card.starts_with(b"ZVAL2   =                    1")));
+
+        let mut zimage = fits::ZImageHeader::new(vec![20, 20]);
+        zimage.bytepix = Some(sample::SampleWidth::Int);
+        let shorts: Vec<i16> = (0..400).map(|x| x * 80 - 16000).collect();
+        fits_roundtrip(&zimage, fits::ImageData::I16(shorts.clone()));
+
+        zimage.bytepix = Some(sample::SampleWidth::Byte);
+        assert!(matches!(
+            fits::write_zimage(Vec::new(), &zimage, &fits::ImageData::I16(shorts)),
+            Err(fits::FitsError::InvalidKeyword(_))
+        ));
+    }
+
+    #[test]
+    fn fits_header_is_fixed_format() {
+        let mut file = Vec::new();
+        fits::write_zimage(
+            &mut file,
+            &fits::ZImageHeader::new(vec![3, 2]),
+            &fits::ImageData::I32(vec![1, 2, 3, 4, 5, 6]),
+        )
+        .unwrap();
+        let cards: Vec<&str> = file[..fits::BLOCK_LEN]
+            .chunks(80)
+            .map(|card| std::str::from_utf8(card).unwrap())
+            .collect();
+        assert_eq!(
+            cards[0].trim_end(),
+            "XTENSION= 'BINTABLE'           / binary table extension"
+        );
+        assert!(cards.iter().any(|card| card.starts_with("TFORM1  = '1PB(")));
+        assert!(cards
+            .iter()
+            .any(|card| card.starts_with("ZIMAGE  =                    T")));
+        assert!(cards
+            .iter()
+            .any(|card| card.starts_with("ZBITPIX =                   32")));
+        assert!(cards
+            .iter()
+            .any(|card| card.starts_with("ZVAL2   =                    1")));
+        assert!(cards.contains(&format!("{:<80}", "END").as_str()));
+    }
+
+    /// Gaussian noise of standard deviation `sigma` around `mean`
+    fn noisy_image(len: usize, mean: f32, sigma: f32) -> Vec<f32> {
+        let mut seed: u32 = 12345;
+        (0..len)
+            .map(|_| {
+                let sum: f32 = (0..12)
+                    .map(|_| {
+                        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                        (seed >> 8) as f32 / (1 << 24) as f32
+                    })
+                    .sum();
+                mean + (sum - 6.0) * sigma
+            })
+            .collect()
+    }
+
+    #[test]
+    fn noise_estimates_work() {
+        use noise::image_stats;
+
+        let image = noisy_image(100 * 50, 1000.0, 10.0);
+        let stats = image_stats(&image, 100, None);
+        assert_eq!(stats.ngood, image.len());
+        for noise in [stats.noise2, stats.noise3, stats.noise5, stats.noise()] {
+            assert!((noise - 10.0).abs() < 1.0, "{:?}", stats);
+        }
+        assert!(stats.min < 970.0 && stats.max > 1030.0);
+
+        /* smooth structure does not add to the noise */
+        let sloped: Vec<f32> = image
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| v + (i % 100) as f32 * 50.0)
+            .collect();
+        assert!((image_stats(&sloped, 100, None).noise3 - stats.noise3).abs() < 0.5);
+
+        /* null pixels are left out, and bring no outliers */
+        let mut blanked = image.clone();
+        for i in (0..blanked.len()).step_by(7) {
+            blanked[i] = -1e30;
+        }
+        blanked[3] = f32::NAN;
+        let nulled = image_stats(&blanked, 100, Some(-1e30));
+        assert_eq!(nulled.ngood, image.len() - image.len().div_ceil(7) - 1);
+        assert!((nulled.noise() - 10.0).abs() < 1.0, "{:?}", nulled);
+        assert!(nulled.min > 900.0);
+
+        /* integer images, rows too short and too few pixels */
+        let ints: Vec<i16> = image.iter().map(|&v| v.round() as i16).collect();
+        assert!((image_stats(&ints, 100, None).noise() - 10.0).abs() < 1.0);
+        let short = image_stats(&ints, 4, None);
+        assert!((short.noise() - 10.0).abs() < 1.0);
+        let tiny = image_stats(&ints[..8], 8, None);
+        assert_eq!((tiny.ngood, tiny.noise()), (8, 0.0));
+        let constant = image_stats(&[7i32; 200], 20, None);
+        assert_eq!((constant.min, constant.max, constant.noise()), (7, 7, 0.0));
+    }
+
+    #[test]
+    fn quantize_roundtrip_works() {
+        use quantize::{Dither, Quantizer};
+
+        let mut image = noisy_image(100 * 50, 1000.0, 10.0);
+        image[17] = 0.0;
+        for dither in [Dither::None, Dither::Subtractive1, Dither::Subtractive2] {
+            let quantizer = Quantizer {
+                dither,
+                ..Quantizer::default()
+            };
+            let mut quantized = vec![0; image.len()];
+            let scale = quantizer.quantize(3, &image, 100, &mut quantized).unwrap();
+            /* the step is about a quarter of the noise */
+            assert!((scale.scale - 2.5).abs() < 0.5, "{:?}", scale);
+
+            let mut restored = vec![0.0; image.len()];
+            quantizer.dequantize(3, &quantized, scale, &mut restored);
+            for (&x, &y) in image.iter().zip(&restored) {
+                assert!(((x - y) as f64).abs() <= scale.scale / 2.0 + 1e-3);
+            }
+            if dither == Dither::Subtractive2 {
+                assert_eq!(restored[17], 0.0);
+            }
+        }
+
+        /* the dither sequence of a tile depends on seed + tile number */
+        let quantizer = Quantizer::default();
+        let mut first = vec![0; image.len()];
+        let mut second = vec![0; image.len()];
+        quantizer.quantize(5, &image, 100, &mut first).unwrap();
+        Quantizer {
+            seed: 6,
+            ..quantizer
+        }
+        .quantize(0, &image, 100, &mut second)
+        .unwrap();
+        assert_eq!(first, second);
+        quantizer.quantize(6, &image, 100, &mut second).unwrap();
+        assert_ne!(first, second);
+
+        /* a negative level gives the step directly */
+        let scale = Quantizer {
+            qlevel: -0.5,
+            ..quantizer
+        }
+        .quantize(0, &image, 100, &mut first)
+        .unwrap();
+        assert_eq!(scale.scale, 0.5);
+
+        /* no noise to scale by */
+        let flat = vec![3.5f32; 1000];
+        assert!(quantizer
+            .quantize(0, &flat, 100, &mut vec![0; 1000])
+            .is_none());
+    }
+
+    #[test]
+    fn fits_float_roundtrip_works() {
+        let mut zimage = fits::ZImageHeader::new(vec![64, 40]);
+        zimage.tile = vec![64, 10];
+        let mut image = noisy_image(64 * 40, 50.0, 3.0);
+        /* a constant tile cannot be quantized and is stored as it is */
+        image[64 * 30..].fill(7.25);
+
+        let mut file = Vec::new();
+        fits::write_zimage(&mut file, &zimage, &fits::ImageData::F32(image.clone())).unwrap();
+        assert!(file.windows(20).any(|w| w == b"SUBTRACTIVE_DITHER_1"));
+        assert!(file.windows(17).any(|w| w == b"UNCOMPRESSED_DATA"));
+
+        let (header, decoded) = fits::read_zimage(&file).unwrap();
+        assert_eq!(header.quantize, zimage.quantize);
+        let decoded = match decoded {
+            fits::ImageData::F32(pixels) => pixels,
+            _ => panic!("not a float image"),
+        };
+        assert_eq!(decoded[64 * 30..], image[64 * 30..]);
+
+        /* the other tiles match quantizing them directly */
+        for tile in 0..3 {
+            let pixels = &image[tile * 640..(tile + 1) * 640];
+            let mut quantized = vec![0; 640];
+            let scale = zimage
+                .quantize
+                .quantize(tile, pixels, 64, &mut quantized)
+                .unwrap();
+            let mut restored = vec![0.0; 640];
+            zimage
+                .quantize
+                .dequantize(tile, &quantized, scale, &mut restored);
+            assert_eq!(decoded[tile * 640..(tile + 1) * 640], restored[..]);
+        }
+
+        zimage.quantize.dither = quantize::Dither::None;
+        let mut file = Vec::new();
+        fits::write_zimage(&mut file, &zimage, &fits::ImageData::F32(image)).unwrap();
+        let (header, _) = fits::read_zimage(&file).unwrap();
+        assert_eq!(header.quantize.dither, quantize::Dither::None);
+        assert!(!file.windows(8).any(|w| w == b"ZDITHER0"));
+    }
+
+    #[test]
+    fn nulls_roundtrip_works() {
+        use nulls::NullMask;
+
+        const BLANK: i16 = -32768;
+        let mut pixels: Vec<i16> = (0..1000).map(|x| 1000 + (x % 7) as i16).collect();
+        for i in (0..pixels.len()).step_by(13).chain([0, 1, 999]) {
+            pixels[i] = BLANK;
+        }
+
+        let mut plain = Vec::new();
+        let plain_len = write::RCEncoder::new(&mut plain)
+            .encode(&pixels, pixels.len(), 32)
+            .unwrap();
+        let mut outarray = Vec::new();
+        let (len, mask) = write::RCEncoder::new(&mut outarray)
+            .encode_nulls(&pixels, 32, BLANK)
+            .unwrap();
+        assert!(len < plain_len / 2, "{} vs {}", len, plain_len);
+        assert_eq!(mask.len(), pixels.len());
+        assert_eq!(mask.count(), pixels.iter().filter(|&&v| v == BLANK).count());
+        assert!(mask.is_null(0) && mask.is_null(13) && !mask.is_null(2));
+
+        let decoder = read::RCDecoder::new();
+        let mut decoded = vec![0; pixels.len()];
+        decoder
+            .decode_nulls(&outarray, 32, &mask, BLANK, &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, pixels);
+
+        /* the mask survives being stored as bytes */
+        let stored = NullMask::from_bytes(mask.as_bytes(), mask.len()).unwrap();
+        assert_eq!(stored, mask);
+        assert!(NullMask::from_bytes(mask.as_bytes(), mask.len() + 8).is_none());
+
+        /* without the mask the nulls read back as their neighbours */
+        decoder
+            .decode(&outarray, mask.len(), 32, &mut decoded)
+            .unwrap();
+        assert_eq!(&decoded[..3], &[1002, 1002, 1002]);
+        assert!(!decoded.contains(&BLANK));
+
+        /* an image of nothing but nulls */
+        let blank = [BLANK; 40];
+        let mut outarray = Vec::new();
+        let (_, mask) = write::RCEncoder::new(&mut outarray)
+            .encode_nulls(&blank, 16, BLANK)
+            .unwrap();
+        assert_eq!(mask.count(), 40);
+        let mut decoded = [0; 40];
+        decoder
+            .decode_nulls(&outarray, 16, &mask, BLANK, &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, blank);
+    }
+
+    #[test]
+    fn quantize_nulls_work() {
+        use quantize::{Dither, Quantizer};
+
+        let mut image = noisy_image(100 * 20, 1000.0, 10.0);
+        for i in (5..image.len()).step_by(11) {
+            image[i] = f32::NAN;
+        }
+        for dither in [Dither::None, Dither::Subtractive1, Dither::Subtractive2] {
+            let quantizer = Quantizer {
+                dither,
+                ..Quantizer::default()
+            };
+            let mut quantized = vec![0; image.len()];
+            let scale = quantizer.quantize(0, &image, 100, &mut quantized).unwrap();
+            assert!((scale.scale - 2.5).abs() < 0.5, "{:?}", scale);
+            /* the nulls sit just below the other values */
+            let max = quantized.iter().max().unwrap();
+            assert!(*max < i32::MIN + 1000, "{}", max);
+
+            let mut restored = vec![0.0; image.len()];
+            quantizer.dequantize(0, &quantized, scale, &mut restored);
+            for (&x, &y) in image.iter().zip(&restored) {
+                assert_eq!(x.is_nan(), y.is_nan());
+                assert!(x.is_nan() || ((x - y) as f64).abs() <= scale.scale / 2.0 + 1e-3);
+            }
+        }
+
+        /* a tile of nothing but nulls */
+        let quantizer = Quantizer::default();
+        let blank = [f32::NAN; 50];
+        let mut quantized = [0; 50];
+        let scale = quantizer.quantize(0, &blank, 10, &mut quantized).unwrap();
+        /* CFITSIO's dummy noise of 1, divided by the level */
+        assert_eq!(
+            scale,
+            quantize::TileScale {
+                scale: 0.25,
+                zero: 0.25 * (2147483647.0 - 10.0)
+            }
+        );
+        assert!(quantized.iter().all(|&v| v == -2147483647));
+        let mut restored = [0.0; 50];
+        quantizer.dequantize(0, &quantized, scale, &mut restored);
+        assert!(restored.iter().all(|v| v.is_nan()));
+    }
+
+    #[test]
+    fn quantize_known_answer() {
+        use quantize::{Dither, Quantizer, TileScale};
+
+        /* worked by hand from fits_quantize_float: tile 0 with ZDITHER0 = 1
+         * starts at the first random value, and the offsets are the first
+         * ten Park & Miller seeds 16807, 282475249, ... over 2^31 - 1 */
+        let tile = [1.0, 2.25, 3.5, 1.75, 0.0, 2.0, 5.0, 4.5, 3.0, 1.25];
+        let quantizer = Quantizer {
+            qlevel: -0.5,
+            dither: Dither::Subtractive1,
+            seed: 1,
+        };
+        let mut quantized = [0; 10];
+        let scale = quantizer.quantize(0, &tile, 10, &mut quantized).unwrap();
+        assert_eq!(
+            scale,
+            TileScale {
+                scale: 0.5,
+                zero: 0.0
+            }
+        );
+        assert_eq!(quantized, [2, 4, 7, 3, 0, 4, 10, 9, 6, 3]);
+        let mut restored = [0.0; 10];
+        quantizer.dequantize(0, &quantized, scale, &mut restored);
+        assert_eq!(
+            restored,
+            [
+                1.2499961,
+                2.184231,
+                3.3721974,
+                1.520675,
+                -0.016383618,
+                2.1405203,
+                5.2264776,
+                4.4105678,
+                2.9103518,
+                1.2826536
+            ]
+        );
+
+        /* SUBTRACTIVE_DITHER_2 shifts the range next to the reserved values
+         * and keeps the zero exact */
+        let quantizer = Quantizer {
+            dither: Dither::Subtractive2,
+            ..quantizer
+        };
+        let scale = quantizer.quantize(0, &tile, 10, &mut quantized).unwrap();
+        assert_eq!(scale.zero, 1073741818.5);
+        assert_eq!(
+            quantized,
+            [
+                -2147483635,
+                -2147483633,
+                -2147483630,
+                -2147483634,
+                -2147483646,
+                -2147483633,
+                -2147483627,
+                -2147483628,
+                -2147483631,
+                -2147483634
+            ]
+        );
+    }
+
+    #[test]
+    fn fits_nulls_work() {
+        /* integer nulls are kept as they are and the value is recorded */
+        let mut zimage = fits::ZImageHeader::new(vec![50, 20]);
+        zimage.blank = Some(-32768);
+        let mut pixels: Vec<i16> = (0..1000).map(|x| (x % 50) as i16).collect();
+        pixels[17] = -32768;
+        let file = fits_roundtrip(&zimage, fits::ImageData::I16(pixels));
+        assert!(file.windows(6).any(|w| w == b"ZBLANK"));
+
+        /* a tile that is mostly nulls comes back exactly, coded just as
+         * encode codes it so CFITSIO reads the same pixels */
+        let mut zimage = fits::ZImageHeader::new(vec![40, 30]);
+        zimage.tile = vec![40, 10];
+        zimage.blank = Some(-32768);
+        let mut pixels: Vec<i16> = (0..1200).map(|x| 100 + (x % 9) as i16).collect();
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            if i < 400 || i % 3 == 0 {
+                *pixel = -32768;
+            }
+        }
+        let file = fits_roundtrip(&zimage, fits::ImageData::I16(pixels.clone()));
+        let mut tile = Vec::new();
+        let len = write::RCEncoder::new(&mut tile)
+            .encode(&pixels[..400], 400, zimage.blocksize)
+            .unwrap();
+        let heap = 2 * fits::BLOCK_LEN + 3 * 8;
+        assert_eq!(&file[heap..heap + len], &tile[..]);
+
+        /* NaN pixels of a float image come back as NaN */
+        let zimage = fits::ZImageHeader::new(vec![64, 10]);
+        let mut image = noisy_image(640, 50.0, 3.0);
+        image[3] = f32::NAN;
+        image[600] = f32::NAN;
+        let mut file = Vec::new();
+        fits::write_zimage(&mut file, &zimage, &fits::ImageData::F32(image.clone())).unwrap();
+        assert!(file.windows(6).any(|w| w == b"ZBLANK"));
+        let (header, decoded) = fits::read_zimage(&file).unwrap();
+        assert_eq!(header.blank, None);
+        let decoded = match decoded {
+            fits::ImageData::F32(pixels) => pixels,
+            _ => panic!("not a float image"),
+        };
+        for (&x, &y) in image.iter().zip(&decoded) {
+            assert_eq!(x.is_nan(), y.is_nan());
+            assert!(x.is_nan() || (x - y).abs() < 1.0);
+        }
+    }
+
+    #[test]
+    fn fits_errors_are_reported() {
+        let zimage = fits::ZImageHeader::new(vec![10, 10]);
+        let data = fits::ImageData::I16((0..100).collect());
+        let mut file = Vec::new();
+        fits::write_primary(&mut file).unwrap();
+        fits::write_zimage(&mut file, &zimage, &data).unwrap();
+
+        assert!(matches!(
+            fits::read_zimage(&file[..fits::BLOCK_LEN]),
+            Err(fits::FitsError::NoCompressedImage)
+        ));
+        assert!(matches!(
+            fits::read_zimage(&file[..2 * fits::BLOCK_LEN + 10]),
+            Err(fits::FitsError::Truncated)
+        ));
+
+        let at = file.windows(6).position(|w| w == b"RICE_1").unwrap();
+        let mut other = file.clone();
+        other[at..at + 6].copy_from_slice(b"GZIP_1");
+        assert!(matches!(
+            fits::read_zimage(&other),
+            Err(fits::FitsError::Unsupported(_))
+        ));
+
+        assert!(matches!(
+            fits::write_zimage(Vec::new(), &fits::ZImageHeader::new(vec![10, 9]), &data),
+            Err(fits::FitsError::DimensionMismatch {
+                expected: 90,
+                found: 100
+            })
+        ));
+
+        /* sizes in a crafted header are errors, not panics or huge
+         * allocations */
+        let set_card = |file: &[u8], keyword: &str, value: &str| {
+            let name = format!("{:<8}= ", keyword);
+            let at = file.windows(10).position(|w| w == name.as_bytes()).unwrap();
+            let mut file = file.to_vec();
+            file[at + 10..at + 30].copy_from_slice(format!("{:>20}", value).as_bytes());
+            file
+        };
+        let huge = set_card(&file, "ZNAXIS", "999999999999999");
+        assert!(matches!(
+            fits::read_zimage(&huge),
+            Err(fits::FitsError::InvalidKeyword(_))
+        ));
+        let huge = set_card(&file, "ZNAXIS1", "4294967296000");
+        let huge = set_card(&huge, "ZNAXIS2", "4294967296000");
+        assert!(matches!(
+            fits::read_zimage(&huge),
+            Err(fits::FitsError::InvalidKeyword(_))
+        ));
+        /* tile and image sizes that fit, but not in the heap */
+        let huge = set_card(&file, "ZNAXIS2", "1000000000000");
+        assert!(fits::read_zimage(&huge).is_err());
+        let huge = set_card(&file, "ZNAXIS1", "1000000000000");
+        let huge = set_card(&huge, "ZTILE1", "1000000000000");
+        assert!(matches!(
+            fits::read_zimage(&huge),
+            Err(fits::FitsError::BadDescriptor { tile: 0 })
+        ));
+
+        /* one all zero block claiming a huge tile through its block size */
+        let zeros = fits::ImageData::I16(vec![0; 32]);
+        let mut file = Vec::new();
+        fits::write_primary(&mut file).unwrap();
+        fits::write_zimage(&mut file, &fits::ZImageHeader::new(vec![32]), &zeros).unwrap();
+        let huge = set_card(&file, "ZNAXIS1", "68719476736");
+        let huge = set_card(&huge, "ZTILE1", "68719476736");
+        let bomb = set_card(&huge, "ZVAL1", "68719476736");
+        assert!(matches!(
+            fits::read_zimage(&bomb),
+            Err(fits::FitsError::InvalidKeyword(keyword)) if keyword == "ZVAL1"
+        ));
+        let bomb = set_card(&huge, "ZVAL1", "65536");
+        assert!(matches!(
+            fits::read_zimage(&bomb),
+            Err(fits::FitsError::BadDescriptor { tile: 0 })
+        ));
+
+        /* table columns whose widths overflow the row */
+        let noisy = fits::ImageData::F32((0..100).map(|x| ((x * 37) % 11) as f32).collect());
+        let mut file = Vec::new();
+        fits::write_primary(&mut file).unwrap();
+        fits::write_zimage(&mut file, &fits::ZImageHeader::new(vec![10, 10]), &noisy).unwrap();
+        let at = file.windows(10).position(|w| w == b"TFORM1  = ").unwrap();
+        let mut wide = file.clone();
+        wide[at + 10..at + 80]
+            .copy_from_slice(format!("{:<70}", "'18446744073709551615B'").as_bytes());
+        assert!(matches!(
+            fits::read_zimage(&wide),
+            Err(fits::FitsError::InvalidKeyword(keyword)) if keyword == "TFORM2"
+        ));
+        assert!(matches!(
+            fits::read_zimage(&set_card(&file, "TFIELDS", "99999999999")),
+            Err(fits::FitsError::InvalidKeyword(keyword)) if keyword == "TFIELDS"
+        ));
+    }
+
+    #[test]
+    fn validate_works() {
+        let inarray: Vec<i32> = (0..1000).map(|x| (x % 97) * (x % 13) * 1000 - 7).collect();
+        let mut outarray = Vec::new();
+        write::RCEncoder::new(&mut outarray)
+            .encode(&inarray, 1000, 32)
+            .unwrap();
+
+        let decoder = read::RCDecoder::new();
+        let width = sample::SampleWidth::Int;
+        assert_eq!(decoder.validate(&outarray, 1000, 32, width).unwrap(), 0);
+
+        let mut padded = outarray.clone();
+        padded.extend_from_slice(&[0; 3]);
+        assert_eq!(decoder.validate(&padded, 1000, 32, width).unwrap(), 3);
+
+        assert!(matches!(
+            decoder.validate(&outarray[..outarray.len() - 1], 1000, 32, width),
+            Err(read::DecodeError::EndOfBuffer { .. })
+        ));
+        assert!(decoder
+            .validate(&outarray, 1000, 32, sample::SampleWidth::Long)
+            .is_err());
+        assert!(matches!(
+            decoder.validate(&outarray, 1000, 0, width),
+            Err(read::DecodeError::ZeroSizeInput)
+        ));
+
+        /* first block header claims fs = 30, above FSMAX */
+        let mut corrupt = outarray.clone();
+        corrupt[4] = 0xff;
+        assert!(matches!(
+            decoder.validate(&corrupt, 1000, 32, width),
+            Err(read::DecodeError::CorruptStream { pixel: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn framed_corruption_is_error() {
+        let mut crc = frame::Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf43926);
+
+        let inarray: Vec<i32> = (0..1000).map(|x| (x % 97) * (x % 13) * 1000 - 7).collect();
+        let decoder = read::RCDecoder::new();
+
+        let mut framed = Vec::new();
+        write::RCEncoder::new(&mut framed)
+            .encode_framed(&inarray, 32, true)
+            .unwrap();
+
+        /* flip one bit of a raw first value, which the decoder cannot detect */
+        let mut corrupt = framed.clone();
+        corrupt[frame::HEADER_LEN + 1] ^= 0x10;
+        assert!(matches!(
+            decoder.decode_framed::<i32>(&corrupt),
+            Err(read::DecodeError::ChecksumMismatch)
+        ));
+
+        let mut corrupt = framed.clone();
+        corrupt[0] = b'X';
+        assert!(matches!(
+            decoder.decode_framed::<i32>(&corrupt),
+            Err(read::DecodeError::InvalidHeader)
+        ));
+
+        /* a pixel count the stream cannot possibly hold */
+        let mut corrupt = framed.clone();
+        corrupt[16..24].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            decoder.decode_framed::<i32>(&corrupt),
+            Err(read::DecodeError::InvalidHeader)
+        ));
+
+        /* a single enormous zero block in a few bytes */
+        let header = frame::FrameHeader {
+            width: sample::SampleWidth::Int,
+            nblock: 1 << 40,
+            nx: 1 << 40,
+            checksum: false,
+        };
+        let mut bomb = header.to_bytes().to_vec();
+        bomb.extend_from_slice(&[0; 6]);
+        assert!(matches!(
+            decoder.decode_framed::<i32>(&bomb),
+            Err(read::DecodeError::InvalidHeader)
+        ));
+
+        /* many large zero blocks, each costing only its 3-bit header */
+        let header = frame::FrameHeader {
+            width: sample::SampleWidth::Byte,
+            nblock: u32::MAX as usize,
+            nx: 20 * u32::MAX as usize,
+            checksum: false,
+        };
+        let mut bomb = header.to_bytes().to_vec();
+        bomb.extend_from_slice(&[0; 9]);
+        assert_eq!(bomb.len(), 33);
+        assert!(matches!(
+            frame::FrameHeader::read(&bomb),
+            Err(read::DecodeError::InvalidHeader)
+        ));
+        assert!(matches!(
+            decoder.decode_framed::<u8>(&bomb),
+            Err(read::DecodeError::InvalidHeader)
+        ));
+
+        /* within the block size limit, but more blocks than the bits allow */
+        bomb[8..16].copy_from_slice(&(frame::MAX_BLOCK as u64).to_be_bytes());
+        bomb[16..24].copy_from_slice(&(24 * frame::MAX_BLOCK as u64 + 1).to_be_bytes());
+        assert!(matches!(
+            decoder.decode_framed::<u8>(&bomb),
+            Err(read::DecodeError::InvalidHeader)
+        ));
+
+        /* a legitimate run of zero blocks, refused only by the caller's limit */
+        let zeros = vec![0u8; 1 << 20];
+        let mut framed_zeros = Vec::new();
+        write::RCEncoder::new(&mut framed_zeros)
+            .encode_framed(&zeros, frame::MAX_BLOCK, false)
+            .unwrap();
+        let mut limited = read::RCDecoder::new();
+        assert_eq!(limited.decode_framed::<u8>(&framed_zeros).unwrap(), zeros);
+        limited.set_max_pixels(1 << 16);
+        assert!(matches!(
+            limited.decode_framed::<u8>(&framed_zeros),
+            Err(read::DecodeError::TooManyPixels {
+                nx: 1048576,
+                max: 65536
+            })
+        ));
+        assert!(matches!(
+            write::RCEncoder::new(Vec::new()).encode_framed(&zeros, frame::MAX_BLOCK + 1, false),
+            Err(write::EncodeError::BlockTooLarge)
+        ));
+
+        assert!(matches!(
+            decoder.decode_framed::<i32>(&framed[..framed.len() - 1]),
+            Err(read::DecodeError::EndOfBuffer { pixel: 1000, .. })
+        ));
+        assert!(matches!(
+            decoder.decode_framed::<i32>(&framed[..10]),
+            Err(read::DecodeError::EndOfBuffer {
+                pixel: 0,
+                offset: 10
+            })
+        ));
+    }
+
+    // Mapped differences of 2^31 and above used to be treated as negative
+    // when Rice coded, corrupting the rest of the stream.
+    #[test]
+    fn bad_encoding() {
         let inarray = [
             -1,
             -1,
@@ -259,10 +1729,258 @@ mod tests {
 
         let decoder = read::RCDecoder::new();
         let mut new_inarray = vec![0; 141];
-        let _result = decoder.decode(&outarray, 141, bs, &mut new_inarray);
+        decoder
+            .decode(&outarray, 141, bs, &mut new_inarray)
+            .unwrap();
 
-        let new_inarray: Vec<i32> = new_inarray.iter().map(|&x| x as i32).collect();
         assert_eq!(new_inarray.len(), 141);
         assert_eq!(inarray.to_vec(), new_inarray);
     }
+
+    // The same block shape scaled up to 64-bit samples, with mapped
+    // differences of 2^63 and above in a Rice coded block.
+    #[test]
+    fn bad_encoding_long() {
+        let mut inarray = vec![-1i64; 141];
+        inarray[2] = -33;
+        inarray[33] = -(1 << 40) - 1;
+        inarray[94] = -(1 << 50) - 1;
+        inarray[131] = -(1 << 62) - 1;
+        let bs = 139;
+
+        roundtrip(&inarray, bs);
+    }
+
+    /// Every vectorised kernel available on this CPU must match the scalar
+    /// pre-pass exactly, for the diffs and for the sum
+    fn simd_matches_scalar<T: sample::RiceSample>(block: &[T], lastpix: T) {
+        let mut expected = vec![0; block.len()];
+        let scalar_sum = simd::map_block_scalar(block, lastpix, &mut expected);
+        let mut diff = vec![0; block.len()];
+        assert_eq!(
+            simd::map_diffs_portable(block, lastpix, &mut diff) as u128,
+            scalar_sum
+        );
+        assert_eq!(diff, expected);
+
+        let mut diff = vec![0; block.len()];
+        assert_eq!(T::map_block(block, lastpix, &mut diff), scalar_sum);
+        assert_eq!(diff, expected);
+    }
+
+    fn simd_kernels_match_scalar_i32(block: &[i32], lastpix: i32) {
+        simd_matches_scalar(block, lastpix);
+        let mut expected = vec![0; block.len()];
+        let sum = simd::map_diffs_portable(block, lastpix, &mut expected);
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut diff = vec![0; block.len()];
+            // SAFETY: SSE2 is part of the x86_64 baseline, and `diff` is as long as `block`.
+            let got = unsafe { simd::x86::map_diffs_i32_sse2(block, lastpix, &mut diff) };
+            assert_eq!(got, sum);
+            assert_eq!(diff, expected);
+            if is_x86_feature_detected!("avx2") {
+                let mut diff = vec![0; block.len()];
+                // SAFETY: AVX2 was detected just above, and `diff` is as long
+                // as `block`.
+                let got = unsafe { simd::x86::map_diffs_i32_avx2(block, lastpix, &mut diff) };
+                assert_eq!(got, sum);
+                assert_eq!(diff, expected);
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            let mut diff = vec![0; block.len()];
+            // SAFETY: NEON is part of the aarch64 baseline, and `diff` is as long as `block`.
+            let got = unsafe { simd::neon::map_diffs_i32(block, lastpix, &mut diff) };
+            assert_eq!(got, sum);
+            assert_eq!(diff, expected);
+        }
+    }
+
+    fn simd_kernels_match_scalar_i16(block: &[i16], lastpix: i16) {
+        simd_matches_scalar(block, lastpix);
+        let mut expected = vec![0; block.len()];
+        let sum = simd::map_diffs_portable(block, lastpix, &mut expected);
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut diff = vec![0; block.len()];
+            // SAFETY: SSE2 is part of the x86_64 baseline, and `diff` is as long as `block`.
+            let got = unsafe { simd::x86::map_diffs_i16_sse2(block, lastpix, &mut diff) };
+            assert_eq!(got, sum);
+            assert_eq!(diff, expected);
+            if is_x86_feature_detected!("avx2") {
+                let mut diff = vec![0; block.len()];
+                // SAFETY: AVX2 was detected just above, and `diff` is as long
+                // as `block`.
+                let got = unsafe { simd::x86::map_diffs_i16_avx2(block, lastpix, &mut diff) };
+                assert_eq!(got, sum);
+                assert_eq!(diff, expected);
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            let mut diff = vec![0; block.len()];
+            // SAFETY: NEON is part of the aarch64 baseline, and `diff` is as long as `block`.
+            let got = unsafe { simd::neon::map_diffs_i16(block, lastpix, &mut diff) };
+            assert_eq!(got, sum);
+            assert_eq!(diff, expected);
+        }
+    }
+
+    #[test]
+    fn simd_extremes_match_scalar() {
+        let ints = [
+            i32::MIN,
+            i32::MAX,
+            0,
+            -1,
+            1,
+            i32::MIN,
+            i32::MIN,
+            i32::MAX,
+            -7,
+        ];
+        let shorts = [
+            i16::MIN,
+            i16::MAX,
+            0,
+            -1,
+            1,
+            i16::MIN,
+            i16::MIN,
+            i16::MAX,
+            -7,
+        ];
+        for len in 0..=40 {
+            let block: Vec<i32> = (0..len).map(|i| ints[i % ints.len()]).collect();
+            simd_kernels_match_scalar_i32(&block, i32::MAX);
+            let block: Vec<i16> = (0..len).map(|i| shorts[i % shorts.len()]).collect();
+            simd_kernels_match_scalar_i16(&block, i16::MAX);
+            let block: Vec<u32> = (0..len).map(|i| ints[i % ints.len()] as u32).collect();
+            simd_matches_scalar(&block, u32::MAX);
+            let block: Vec<u16> = (0..len).map(|i| shorts[i % shorts.len()] as u16).collect();
+            simd_matches_scalar(&block, 0u16);
+        }
+    }
+
+    /// The floating point estimate from ricecomp.c
+    fn split_fs_f64(pixelsum: u64, thisblock: usize) -> i32 {
+        let mut dpsum = (pixelsum as f64 - ((thisblock / 2) as f64) - 1.0) / (thisblock as f64);
+        if dpsum < 0.0 {
+            dpsum = 0.0;
+        }
+        let mut psum = (dpsum as u64) >> 1;
+        let mut fs = 0;
+        while psum > 0 {
+            psum >>= 1;
+            fs += 1;
+        }
+        fs
+    }
+
+    #[test]
+    fn split_fs_matches_float_exhaustively() {
+        /* every sum up to an average mapped difference of 300 */
+        for thisblock in 1..=64 {
+            for pixelsum in 0..=thisblock as u64 * 300 {
+                assert_eq!(
+                    write::split_fs(pixelsum as u128, thisblock),
+                    split_fs_f64(pixelsum, thisblock),
+                    "sum {} block {}",
+                    pixelsum,
+                    thisblock
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn split_fs_matches_float_at_boundaries() {
+        let blocks = (1..=1024).chain([4095, 4096, 65535, 65536, 1 << 20, (1 << 21) - 1, 1 << 21]);
+        for thisblock in blocks {
+            /* largest sum a block of 32-bit pixels can have */
+            let max = thisblock as u64 * u32::MAX as u64;
+            let mut sums = vec![max, max - 1, 0, 1, 2];
+            for k in 0..=33 {
+                /* smallest sum with psum >= 2^k, where fs steps from k to k + 1 */
+                let edge = ((thisblock as u64) << (k + 1)) + (thisblock / 2) as u64 + 1;
+                sums.extend(edge.saturating_sub(3)..=edge + 3);
+            }
+            for pixelsum in sums.into_iter().filter(|&sum| sum <= max) {
+                assert_eq!(
+                    write::split_fs(pixelsum as u128, thisblock),
+                    split_fs_f64(pixelsum, thisblock),
+                    "sum {} block {}",
+                    pixelsum,
+                    thisblock
+                );
+            }
+        }
+
+        /* sums of 64-bit blocks that no float can hold exactly */
+        assert_eq!(write::split_fs(u64::MAX as u128 * 32, 32), 63);
+        assert_eq!(write::split_fs(0, 1 << 40), 0);
+    }
+
+    fn sample_strategy<T: std::fmt::Debug + Copy + 'static>(
+        extremes: &'static [T],
+        any: impl Strategy<Value = T> + Clone + 'static,
+    ) -> impl Strategy<Value = Vec<T>> {
+        /* mostly smooth runs with occasional extreme outliers */
+        let value = prop_oneof![
+            6 => proptest::sample::select(extremes),
+            1 => any,
+        ];
+        proptest::collection::vec(value, 1..600)
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip_byte(input in sample_strategy(&[0i8, 1, -1, 2, i8::MIN, i8::MAX], any::<i8>()), bs in 1usize..300) {
+            roundtrip(&input, bs);
+        }
+
+        #[test]
+        fn roundtrip_short(input in sample_strategy(&[0i16, 1, -1, 2, i16::MIN, i16::MAX], any::<i16>()), bs in 1usize..300) {
+            roundtrip(&input, bs);
+        }
+
+        #[test]
+        fn roundtrip_int(input in sample_strategy(&[0i32, 1, -1, -33, -1281, i32::MIN, i32::MAX, -(1 << 30) - 1], any::<i32>()), bs in 1usize..300) {
+            roundtrip(&input, bs);
+        }
+
+        #[test]
+        fn roundtrip_long(input in sample_strategy(&[0i64, 1, -1, -33, i64::MIN, i64::MAX, -(1 << 62) - 1], any::<i64>()), bs in 1usize..300) {
+            roundtrip(&input, bs);
+        }
+
+        #[test]
+        fn stream_matches_encode_short(input in sample_strategy(&[0i16, 1, -1, 2, i16::MIN, i16::MAX], any::<i16>()), bs in 1usize..300, chunk in 1usize..700) {
+            stream_matches_encode(&input, bs, chunk);
+        }
+
+        #[test]
+        fn stream_decode_matches_decode_int(input in sample_strategy(&[0i32, 1, -1, -33, i32::MIN, i32::MAX], any::<i32>()), bs in 1usize..300, chunk in 1usize..700) {
+            stream_decode_matches_decode(&input, bs, chunk);
+        }
+
+        #[test]
+        fn simd_matches_scalar_int(block in sample_strategy(&[0i32, 1, -1, -33, i32::MIN, i32::MAX], any::<i32>()), lastpix in any::<i32>()) {
+            simd_kernels_match_scalar_i32(&block, lastpix);
+        }
+
+        #[test]
+        fn simd_matches_scalar_short(block in sample_strategy(&[0i16, 1, -1, 2, i16::MIN, i16::MAX], any::<i16>()), lastpix in any::<i16>()) {
+            simd_kernels_match_scalar_i16(&block, lastpix);
+        }
+
+        #[test]
+        fn roundtrip_unsigned(input in sample_strategy(&[0u32, 1, u32::MAX, 1 << 31], any::<u32>()), bs in 1usize..300) {
+            roundtrip(&input, bs);
+        }
+    }
 }
@@ -0,0 +1,322 @@
+//! Background noise estimates for choosing quantization levels.
+//!
+//! These are the median absolute difference estimators of `fits_img_stats`,
+//! developed for the signal to noise of spectra (ST-ECF newsletter issue 42).
+//! Along each row the n-th order differences of pixels two apart cancel
+//! smooth structure, leaving the noise:
+//!
+//! | order | difference                                   | scale factor      |
+//! |-------|----------------------------------------------|-------------------|
+//! | 2nd   | `v5 - v7`                                    | 1.482602 / √2     |
+//! | 3rd   | `2 v5 - v3 - v7`                             | 1.482602 / √6     |
+//! | 5th   | `6 v5 - 4 v3 - 4 v7 + v1 + v9`               | 1.482602 / √70    |
+//!
+//! The median of the absolute differences is taken for each row, and the
+//! estimate is the median of those over the rows, scaled to a Gaussian
+//! standard deviation. Runs of constant background are left out, as are null
+//! pixels. Higher orders are less biased by structure in the image but
+//! noisier themselves.
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for i16 {}
+    impl Sealed for i32 {}
+    impl Sealed for f32 {}
+    impl Sealed for f64 {}
+}
+
+/// A pixel type the noise can be estimated for.
+///
+/// Differences of floating point pixels are taken at their own precision and
+/// those of integer pixels exactly, as the CFITSIO routine for each type does.
+pub trait NoiseSample: Copy + PartialOrd + private::Sealed {
+    #[doc(hidden)]
+    type Diff: Copy + PartialOrd + Default + Into<f64>;
+    #[doc(hidden)]
+    const MIN: Self;
+    #[doc(hidden)]
+    const MAX: Self;
+
+    /// The pixel is missing: equal to `null`, or NaN
+    #[doc(hidden)]
+    fn is_null(self, null: Option<Self>) -> bool {
+        null == Some(self)
+    }
+
+    #[doc(hidden)]
+    fn diff2(v5: Self, v7: Self) -> Self::Diff;
+    #[doc(hidden)]
+    fn diff3(v3: Self, v5: Self, v7: Self) -> Self::Diff;
+    #[doc(hidden)]
+    fn diff5(v1: Self, v3: Self, v5: Self, v7: Self, v9: Self) -> Self::Diff;
+}
+
+macro_rules! float_noise_sample {
+    ($t:ty) => {
+        impl NoiseSample for $t {
+            type Diff = $t;
+            const MIN: Self = <$t>::MAX;
+            const MAX: Self = -<$t>::MAX;
+
+            fn is_null(self, null: Option<Self>) -> bool {
+                self.is_nan() || null == Some(self)
+            }
+
+            fn diff2(v5: Self, v7: Self) -> Self::Diff {
+                (v5 - v7).abs()
+            }
+
+            fn diff3(v3: Self, v5: Self, v7: Self) -> Self::Diff {
+                (2.0 * v5 - v3 - v7).abs()
+            }
+
+            fn diff5(v1: Self, v3: Self, v5: Self, v7: Self, v9: Self) -> Self::Diff {
+                (6.0 * v5 - 4.0 * v3 - 4.0 * v7 + v1 + v9).abs()
+            }
+        }
+    };
+}
+
+macro_rules! int_noise_sample {
+    ($t:ty) => {
+        impl NoiseSample for $t {
+            type Diff = f64;
+            const MIN: Self = <$t>::MAX;
+            const MAX: Self = <$t>::MIN;
+
+            fn diff2(v5: Self, v7: Self) -> Self::Diff {
+                (v5 as f64 - v7 as f64).abs()
+            }
+
+            fn diff3(v3: Self, v5: Self, v7: Self) -> Self::Diff {
+                (2.0 * v5 as f64 - v3 as f64 - v7 as f64).abs()
+            }
+
+            fn diff5(v1: Self, v3: Self, v5: Self, v7: Self, v9: Self) -> Self::Diff {
+                (6.0 * v5 as f64 - 4.0 * v3 as f64 - 4.0 * v7 as f64 + v1 as f64 + v9 as f64).abs()
+            }
+        }
+    };
+}
+
+float_noise_sample!(f32);
+float_noise_sample!(f64);
+int_noise_sample!(i16);
+int_noise_sample!(i32);
+
+/// Pixel range and noise estimates of an image or tile
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageStats<T> {
+    /// Number of pixels that are not null. Rows with fewer than 8 such pixels
+    /// are not counted, as in CFITSIO.
+    pub ngood: usize,
+    /// Smallest and largest pixel that is not null. With no such pixels these
+    /// are the largest and smallest values of the type.
+    pub min: T,
+    pub max: T,
+    /// 2nd order estimate, 0 if no row has two differences to compare
+    pub noise2: f64,
+    /// 3rd order estimate, 0 if no row has 9 or more valid pixels
+    pub noise3: f64,
+    /// 5th order estimate, 0 if no row has 9 or more valid pixels
+    pub noise5: f64,
+}
+
+impl<T> ImageStats<T> {
+    /// The smallest estimate that is not 0, as used to quantize a tile
+    pub fn noise(&self) -> f64 {
+        let mut noise = self.noise3;
+        if self.noise2 != 0.0 && self.noise2 < noise {
+            noise = self.noise2;
+        }
+        if self.noise5 != 0.0 && self.noise5 < noise {
+            noise = self.noise5;
+        }
+        noise
+    }
+}
+
+/// Pixel range and 2nd, 3rd and 5th order noise estimates of `input`, a
+/// row-major image with rows of `nx` pixels, skipping pixels equal to `null`
+/// and NaN. This is `FnNoise5` from CFITSIO.
+///
+/// Images with rows shorter than 9 pixels are treated as a single row; with
+/// fewer than 9 pixels in all every estimate is 0.
+pub fn image_stats<T: NoiseSample>(input: &[T], nx: usize, null: Option<T>) -> ImageStats<T> {
+    let mut stats = ImageStats {
+        ngood: 0,
+        min: T::MIN,
+        max: T::MAX,
+        noise2: 0.0,
+        noise3: 0.0,
+        noise5: 0.0,
+    };
+    let (mut min, mut max) = (T::MIN, T::MAX);
+    let mut range = |v: T| {
+        if v < min {
+            min = v;
+        }
+        if v > max {
+            max = v;
+        }
+    };
+
+    /* rows must have at least 9 pixels, else treat the image as one row */
+    let ny = input.len().checked_div(nx).unwrap_or(0);
+    let (nx, ny) = if nx < 9 { (nx * ny, 1) } else { (nx, ny) };
+    if nx < 9 {
+        for &v in input[..nx].iter().filter(|v| !v.is_null(null)) {
+            range(v);
+            stats.ngood += 1;
+        }
+        stats.min = min;
+        stats.max = max;
+        return stats;
+    }
+
+    let mut ngood = 0;
+    let mut differences2 = vec![T::Diff::default(); nx];
+    let mut differences3 = vec![T::Diff::default(); nx];
+    let mut differences5 = vec![T::Diff::default(); nx];
+    let mut diffs2 = Vec::with_capacity(ny);
+    let mut diffs3 = Vec::with_capacity(ny);
+    let mut diffs5 = Vec::with_capacity(ny);
+
+    for row in input.chunks_exact(nx).take(ny) {
+        let mut valid = row.iter().copied().filter(|v| !v.is_null(null));
+
+        /* the first 8 valid pixels fill the window */
+        let mut window = [row[0]; 9];
+        let mut nfirst = 0;
+        for v in valid.by_ref().take(8) {
+            range(v);
+            window[nfirst + 1] = v;
+            nfirst += 1;
+        }
+        if nfirst < 8 {
+            continue;
+        }
+
+        let mut nvals = 0;
+        let mut nvals2 = 0;
+        let mut nconstant = 0;
+        for v in valid {
+            range(v);
+            window.copy_within(1.., 0);
+            window[8] = v;
+            let [v1, _, v3, v4, v5, v6, v7, _, v9] = window;
+
+            if !(v5 == v6 && v6 == v7) {
+                differences2[nvals2] = T::diff2(v5, v7);
+                nvals2 += 1;
+            }
+            /* ignore constant background regions */
+            if !(v3 == v4 && v4 == v5 && v5 == v6 && v6 == v7) {
+                differences3[nvals] = T::diff3(v3, v5, v7);
+                differences5[nvals] = T::diff5(v1, v3, v5, v7, v9);
+                nvals += 1;
+            } else {
+                nconstant += 1;
+            }
+        }
+        ngood += nvals + nconstant + 8;
+
+        match nvals {
+            0 => continue,
+            1 => {
+                if nvals2 == 1 {
+                    diffs2.push(differences2[0].into());
+                }
+                diffs3.push(differences3[0].into());
+                diffs5.push(differences5[0].into());
+            }
+            _ => {
+                /* CFITSIO takes the median of the first nvals (not nvals2)
+                 * 2nd order differences; stale values from earlier rows are
+                 * kept to give the same estimate */
+                if nvals2 > 1 {
+                    diffs2.push(quick_select(&mut differences2[..nvals]).into());
+                }
+                diffs3.push(quick_select(&mut differences3[..nvals]).into());
+                diffs5.push(quick_select(&mut differences5[..nvals]).into());
+            }
+        }
+    }
+
+    stats.ngood = ngood;
+    stats.min = min;
+    stats.max = max;
+    stats.noise2 = 1.0483579 * median(&mut diffs2);
+    stats.noise3 = 0.6052697 * median(&mut diffs3);
+    stats.noise5 = 0.1772048 * median(&mut diffs5);
+    stats
+}
+
+/// Median of the per-row values, averaging the middle two; 0 if empty
+fn median(values: &mut [f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(f64::total_cmp);
+    let n = values.len();
+    (values[(n - 1) / 2] + values[n / 2]) / 2.0
+}
+
+/// Lower median of `values`, partially reordering them exactly as the
+/// `quick_select` routine of CFITSIO does
+fn quick_select<D: Copy + PartialOrd>(values: &mut [D]) -> D {
+    let mut low = 0;
+    let mut high = values.len() - 1;
+    let median = high / 2;
+    loop {
+        if high <= low {
+            return values[median];
+        }
+        if high == low + 1 {
+            if values[low] > values[high] {
+                values.swap(low, high);
+            }
+            return values[median];
+        }
+
+        /* find the median of the low, middle and high items; swap into low */
+        let middle = (low + high) / 2;
+        if values[middle] > values[high] {
+            values.swap(middle, high);
+        }
+        if values[low] > values[high] {
+            values.swap(low, high);
+        }
+        if values[middle] > values[low] {
+            values.swap(middle, low);
+        }
+        values.swap(middle, low + 1);
+
+        /* nibble from each end towards the middle, swapping when stuck */
+        let mut ll = low + 1;
+        let mut hh = high;
+        loop {
+            ll += 1;
+            while values[low] > values[ll] {
+                ll += 1;
+            }
+            hh -= 1;
+            while values[hh] > values[low] {
+                hh -= 1;
+            }
+            if hh < ll {
+                break;
+            }
+            values.swap(ll, hh);
+        }
+        values.swap(low, hh);
+
+        if hh <= median {
+            low = ll;
+        }
+        if hh >= median {
+            high = hh - 1;
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Null pixels kept out of the Rice differences, for
+//! [`RCEncoder::encode_nulls`](crate::write::RCEncoder::encode_nulls) and
+//! [`RCDecoder::decode_nulls`](crate::read::RCDecoder::decode_nulls).
+//!
+//! A null pixel, such as one equal to the FITS `BLANK` value, is usually far
+//! from its neighbours, so coding it as it is costs two large differences.
+//! Instead each null is coded as a copy of the pixel before it, which adds a
+//! zero difference and leaves the difference between the pixels either side
+//! unchanged. Which pixels were null is recorded in a [`NullMask`] that has to
+//! be stored with the stream; the decoder puts the null value back.
+//!
+//! Without the mask the stream still decodes, with the nulls filled in.
+//!
+//! This is a standalone API for raw streams. FITS tiles have nowhere to keep
+//! the mask, so the [`fits`](crate::fits) module codes integer nulls as they
+//! are, as CFITSIO does.
+
+/// Which pixels of an image are null, one bit per pixel
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct NullMask {
+    len: usize,
+    /// Most significant bit first, as in a FITS `X` column
+    bits: Vec<u8>,
+}
+
+impl NullMask {
+    /// Mask of the pixels of `input` equal to `null`
+    pub fn from_pixels<T: PartialEq>(input: &[T], null: T) -> NullMask {
+        let mut bits = vec![0; input.len().div_ceil(8)];
+        for (i, _) in input.iter().enumerate().filter(|(_, v)| **v == null) {
+            bits[i / 8] |= 0x80 >> (i % 8);
+        }
+        NullMask {
+            len: input.len(),
+            bits,
+        }
+    }
+
+    /// Mask of `len` pixels from the bytes of [`as_bytes`](Self::as_bytes).
+    /// Returns `None` if there are not `len / 8` bytes, rounded up.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Option<NullMask> {
+        if bytes.len() != len.div_ceil(8) {
+            return None;
+        }
+        let mut bits = bytes.to_vec();
+        /* clear the padding so equal masks compare equal */
+        if let Some(last) = bits.last_mut().filter(|_| !len.is_multiple_of(8)) {
+            *last &= !(0xff >> (len % 8));
+        }
+        Some(NullMask { len, bits })
+    }
+
+    /// The mask packed 8 pixels to a byte, most significant bit first
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// Number of pixels covered, null or not
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_null(&self, pixel: usize) -> bool {
+        pixel < self.len && self.bits[pixel / 8] & (0x80 >> (pixel % 8)) != 0
+    }
+
+    /// Number of null pixels
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    /// Copy of `input` with each null replaced by the pixel before it, or by
+    /// the first pixel that is not null for nulls at the start
+    pub(crate) fn fill<T: Copy>(&self, input: &[T]) -> Vec<T> {
+        let Some(mut last) = (0..input.len())
+            .find(|&i| !self.is_null(i))
+            .map(|i| input[i])
+        else {
+            return input.to_vec();
+        };
+        input
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| {
+                if !self.is_null(i) {
+                    last = v;
+                }
+                last
+            })
+            .collect()
+    }
+
+    /// Set the null pixels of `output` to `null`
+    pub(crate) fn restore<T: Copy>(&self, output: &mut [T], null: T) {
+        for (i, v) in output.iter_mut().enumerate() {
+            if self.is_null(i) {
+                *v = null;
+            }
+        }
+    }
+}
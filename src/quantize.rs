@@ -0,0 +1,278 @@
+//! Quantization of floating point tiles to integers, so they can be Rice
+//! coded.
+//!
+//! Section 6 of the FITS tiled image convention compresses `BITPIX = -32`
+//! images by scaling each tile to integers with a step tied to the noise in
+//! the tile. The step is the background noise (the smallest of the 2nd, 3rd
+//! and 5th order median absolute difference estimates) divided by the level
+//! `q`, so larger values of `q` keep more of the noise and compress less. The
+//! tile is restored as `value = (i - dither) * ZSCALE + ZZERO`.
+//!
+//! Subtractive dithering adds a reproducible random offset to each pixel
+//! before rounding and removes it again on restoring, which keeps the mean of
+//! the restored values unbiased. The offsets come from the standard sequence
+//! of 10000 values CFITSIO uses, so tiles quantized here are restored the
+//! same by CFITSIO and vice versa.
+//!
+//! NaN pixels are quantized to a reserved integer, the value of the `ZBLANK`
+//! keyword, and the tile is shifted so its other values sit just above it.
+
+use crate::noise;
+
+/// Length of the dithering random number sequence
+pub const N_RANDOM: usize = 10000;
+
+/// Quantization level used when `q` is 0
+pub const DEFAULT_QLEVEL: f32 = 4.0;
+
+/// Reserved integer marking a null pixel
+pub(crate) const NULL_VALUE: i32 = -2147483647;
+/// Reserved integer marking an exact zero with `SUBTRACTIVE_DITHER_2`
+const ZERO_VALUE: i32 = -2147483646;
+/// Integers at the bottom of the range kept free for reserved values
+const N_RESERVED_VALUES: f64 = 10.0;
+
+/// Random numbers in [0, 1) from the Park & Miller minimal standard
+/// generator, as `fits_init_randoms` computes them
+static RANDOM_VALUES: [f32; N_RANDOM] = {
+    let a = 16807.0;
+    let m = 2147483647.0;
+    let mut seed: f64 = 1.0;
+    let mut values = [0.0; N_RANDOM];
+    let mut n = 0;
+    while n < N_RANDOM {
+        let temp = a * seed;
+        seed = temp - m * ((temp / m) as i32 as f64);
+        values[n] = (seed / m) as f32;
+        n += 1;
+    }
+    /* the check value of the sequence from the FITS convention */
+    assert!(seed as i32 == 1043618065);
+    values
+};
+
+/// How quantized tiles are dithered, the `ZQUANTIZ` keyword
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Dither {
+    /// Plain rounding, `NO_DITHER`
+    None,
+    /// Subtractive dithering of every pixel, `SUBTRACTIVE_DITHER_1`
+    #[default]
+    Subtractive1,
+    /// As `Subtractive1`, but pixels that are exactly 0.0 are kept exact,
+    /// `SUBTRACTIVE_DITHER_2`
+    Subtractive2,
+}
+
+impl Dither {
+    /// Value of the `ZQUANTIZ` keyword
+    pub fn keyword(self) -> &'static str {
+        match self {
+            Dither::None => "NO_DITHER",
+            Dither::Subtractive1 => "SUBTRACTIVE_DITHER_1",
+            Dither::Subtractive2 => "SUBTRACTIVE_DITHER_2",
+        }
+    }
+
+    pub fn from_keyword(keyword: &str) -> Option<Dither> {
+        match keyword {
+            "NO_DITHER" => Some(Dither::None),
+            "SUBTRACTIVE_DITHER_1" => Some(Dither::Subtractive1),
+            "SUBTRACTIVE_DITHER_2" => Some(Dither::Subtractive2),
+            _ => None,
+        }
+    }
+}
+
+/// Scaling of one quantized tile, the `ZSCALE` and `ZZERO` columns
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TileScale {
+    pub scale: f64,
+    pub zero: f64,
+}
+
+/// Quantization settings for an image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quantizer {
+    /// Quantization level `q`: the step is the noise estimate divided by
+    /// `q`. 0 selects [`DEFAULT_QLEVEL`] and a negative value gives the step
+    /// directly as `-q`.
+    pub qlevel: f32,
+    pub dither: Dither,
+    /// Offset into the random number sequence for the first tile, 1 to
+    /// 10000, the `ZDITHER0` keyword
+    pub seed: usize,
+}
+
+impl Default for Quantizer {
+    fn default() -> Self {
+        Quantizer {
+            qlevel: DEFAULT_QLEVEL,
+            dither: Dither::default(),
+            seed: 1,
+        }
+    }
+}
+
+impl Quantizer {
+    /// Quantize tile number `tile` (counting from 0) of `input`, made of rows
+    /// of `nx` pixels, into `output`. This is `fits_quantize_float`.
+    ///
+    /// NaN pixels are null: they are coded as the reserved `ZBLANK` value
+    /// and left out of the noise estimate and the range.
+    ///
+    /// Returns `None`, leaving `output` unspecified, if the tile cannot be
+    /// quantized: it has no noise to scale by, or its range does not fit in
+    /// 32-bit integers at the chosen step. Such tiles have to be stored
+    /// losslessly instead.
+    pub fn quantize(
+        &self,
+        tile: usize,
+        input: &[f32],
+        nx: usize,
+        output: &mut [i32],
+    ) -> Option<TileScale> {
+        assert_eq!(input.len(), output.len());
+        if input.len() <= 1 {
+            return None;
+        }
+
+        /* NaN pixels are null and quantized to NULL_VALUE */
+        let nulls = input.iter().any(|v| v.is_nan());
+        let (delta, min, max) = if self.qlevel >= 0.0 {
+            let qlevel = if self.qlevel == 0.0 {
+                DEFAULT_QLEVEL
+            } else {
+                self.qlevel
+            };
+            if nulls && input.iter().all(|v| v.is_nan()) {
+                /* dummy values, as every pixel is null. CFITSIO also takes
+                 * them when no row has 8 valid pixels, but then quantizes the
+                 * valid ones against a made-up range; here their noise of 0
+                 * leaves the tile to be stored losslessly instead */
+                (1.0 / qlevel as f64, 0.0, 1.0)
+            } else {
+                let stats = noise::image_stats(input, nx, None);
+                (stats.noise() / qlevel as f64, stats.min, stats.max)
+            }
+        } else {
+            /* an all null tile keeps the initial range, as in CFITSIO */
+            let (min, max) = input.iter().fold((f32::MAX, -f32::MAX), |(min, max), &v| {
+                (min.min(v), max.max(v))
+            });
+            (-self.qlevel as f64, min, max)
+        };
+        if delta == 0.0 {
+            return None;
+        }
+
+        /* the range of quantized levels must fit in an int */
+        let range = (max - min) as f64 / delta;
+        if range > 2.0 * 2147483647.0 - N_RESERVED_VALUES {
+            return None;
+        }
+
+        /* keep the values positive where possible, which codes better */
+        let zero = if self.dither == Dither::Subtractive2 || nulls {
+            /* shift the range close to the reserved values, so the nulls
+             * stay near the other pixels */
+            min as f64 - delta * (NULL_VALUE as f64 + N_RESERVED_VALUES)
+        } else if range < 2147483647.0 - N_RESERVED_VALUES {
+            /* a whole multiple of the step, so requantizing is stable */
+            ((min as f64 / delta + 0.5) as i64) as f64 * delta
+        } else {
+            /* center the quantized levels around zero */
+            (min as f64 + max as f64) / 2.0
+        };
+
+        if self.dither == Dither::None {
+            for (out, &value) in output.iter_mut().zip(input) {
+                *out = if value.is_nan() {
+                    NULL_VALUE
+                } else {
+                    nint((value as f64 - zero) / delta)
+                };
+            }
+        } else {
+            let mut random = RandomSequence::new(self.seed + tile);
+            for (out, &value) in output.iter_mut().zip(input) {
+                let offset = random.next();
+                *out = if value.is_nan() {
+                    NULL_VALUE
+                } else if self.dither == Dither::Subtractive2 && value == 0.0 {
+                    ZERO_VALUE
+                } else {
+                    nint((value as f64 - zero) / delta + offset - 0.5)
+                };
+            }
+        }
+
+        Some(TileScale { scale: delta, zero })
+    }
+
+    /// Restore tile number `tile` quantized with `scale` into `output`, with
+    /// null pixels as NaN. This is `unquantize_i4r4`.
+    pub fn dequantize(&self, tile: usize, input: &[i32], scale: TileScale, output: &mut [f32]) {
+        assert_eq!(input.len(), output.len());
+        if self.dither == Dither::None {
+            for (out, &value) in output.iter_mut().zip(input) {
+                *out = if value == NULL_VALUE {
+                    f32::NAN
+                } else {
+                    (value as f64 * scale.scale + scale.zero) as f32
+                };
+            }
+            return;
+        }
+
+        let mut random = RandomSequence::new(self.seed + tile);
+        for (out, &value) in output.iter_mut().zip(input) {
+            let offset = random.next();
+            *out = if value == NULL_VALUE {
+                f32::NAN
+            } else if self.dither == Dither::Subtractive2 && value == ZERO_VALUE {
+                0.0
+            } else {
+                ((value as f64 - offset + 0.5) * scale.scale + scale.zero) as f32
+            };
+        }
+    }
+}
+
+/// Walks the random number sequence the way CFITSIO does for one tile: the
+/// tile's seed picks a starting value, and each time the end of the sequence
+/// is reached the next seed picks a new one.
+struct RandomSequence {
+    iseed: usize,
+    next: usize,
+}
+
+impl RandomSequence {
+    /// Sequence for tile `row` counting from 1, offset by `ZDITHER0 - 1`
+    fn new(row: usize) -> Self {
+        let iseed = (row + N_RANDOM - 1) % N_RANDOM;
+        RandomSequence {
+            iseed,
+            next: (RANDOM_VALUES[iseed] * 500.0) as usize,
+        }
+    }
+
+    fn next(&mut self) -> f64 {
+        let value = RANDOM_VALUES[self.next] as f64;
+        self.next += 1;
+        if self.next == N_RANDOM {
+            self.iseed = (self.iseed + 1) % N_RANDOM;
+            self.next = (RANDOM_VALUES[self.iseed] * 500.0) as usize;
+        }
+        value
+    }
+}
+
+/// Round to the nearest integer, halves away from zero, as the C `NINT` macro
+fn nint(x: f64) -> i32 {
+    if x >= 0.0 {
+        (x + 0.5) as i32
+    } else {
+        (x - 0.5) as i32
+    }
+}
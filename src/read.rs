@@ -1,29 +1,109 @@
-use std::ffi::{c_uchar, c_uint, c_ushort};
-
-use crate::log_noop;
-
-/// nonzero_count is lookup table giving number of bits in 8-bit values not including
-/// leading zeros used in fits_rdecomp, fits_rdecomp_short and fits_rdecomp_byte
-const NONZERO_COUNT: [i32; 256] = [
-    0, 1, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 4, 4, 4, 4, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5,
-    6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6,
-    7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
-    7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
-    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
-    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
-    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
-    8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
-];
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+
+use crate::{
+    frame::{self, FrameHeader},
+    log_noop,
+    nulls::NullMask,
+    sample::{self, RiceSample, SampleWidth},
+    stats::{BlockKind, BlockStats},
+};
 
 #[derive(Debug)]
 pub enum DecodeError {
-    EndOfBuffer,
+    /// The compressed stream ended while decoding pixel `pixel`; `offset` is
+    /// the byte offset into the input that could not be read.
+    EndOfBuffer {
+        pixel: usize,
+        offset: usize,
+    },
     ZeroSizeInput,
     NotProperlyAllocated,
+    /// The stream contains a block header or code that the encoder can never
+    /// produce, found while decoding pixel `pixel` at byte offset `offset`.
+    CorruptStream {
+        pixel: usize,
+        offset: usize,
+    },
+    /// Reading the compressed stream failed
+    Io(io::Error),
+    /// The input does not start with a valid frame header
+    InvalidHeader,
+    /// The framed stream holds samples of a different width than requested
+    WrongSampleWidth {
+        expected: SampleWidth,
+        found: SampleWidth,
+    },
+    /// The checksum of a framed stream does not match its contents
+    ChecksumMismatch,
+    /// An earlier call to [`RCStreamDecoder::read_pixels`] failed, so the
+    /// stream cannot be continued
+    Poisoned,
+    /// A framed stream holds more pixels than the limit set with
+    /// [`RCDecoder::set_max_pixels`]
+    TooManyPixels {
+        nx: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::EndOfBuffer { pixel, offset } => write!(
+                f,
+                "hit end of compressed byte stream at pixel {} (byte offset {})",
+                pixel, offset
+            ),
+            DecodeError::ZeroSizeInput => write!(f, "zero size input"),
+            DecodeError::NotProperlyAllocated => write!(f, "buffer not properly allocated"),
+            DecodeError::CorruptStream { pixel, offset } => write!(
+                f,
+                "corrupt compressed byte stream at pixel {} (byte offset {})",
+                pixel, offset
+            ),
+            DecodeError::Io(err) => write!(f, "error reading input: {}", err),
+            DecodeError::InvalidHeader => write!(f, "invalid frame header"),
+            DecodeError::WrongSampleWidth { expected, found } => write!(
+                f,
+                "stream holds {}-byte samples, not {}-byte",
+                found.bytepix(),
+                expected.bytepix()
+            ),
+            DecodeError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            DecodeError::Poisoned => write!(f, "stream decoder used after an error"),
+            DecodeError::TooManyPixels { nx, max } => {
+                write!(
+                    f,
+                    "stream holds {} pixels, more than the limit of {}",
+                    nx, max
+                )
+            }
+        }
+    }
 }
 
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(err: io::Error) -> Self {
+        DecodeError::Io(err)
+    }
+}
+
+/// Largest number of pixels an output sized from a header grows by at a time
+const DECODE_CHUNK: usize = 1 << 16;
+
 pub struct RCDecoder {
     log_fn: fn(&str),
+    max_pixels: usize, /* largest framed stream to decode */
 }
 
 impl Default for RCDecoder {
@@ -34,519 +114,826 @@ impl Default for RCDecoder {
 
 impl RCDecoder {
     pub fn new() -> RCDecoder {
-        RCDecoder { log_fn: log_noop }
+        RCDecoder {
+            log_fn: log_noop,
+            max_pixels: usize::MAX,
+        }
     }
 
     pub fn set_log_fn(&mut self, log_fn: fn(&str)) {
         self.log_fn = log_fn;
     }
 
-    pub fn decode(
+    /// Refuse framed streams of more than `max_pixels` pixels in
+    /// [`decode_framed`](Self::decode_framed). All zero blocks cost a few bits
+    /// each, so a short stream can legitimately hold a very large image; set
+    /// this when decoding untrusted input. There is no limit by default.
+    pub fn set_max_pixels(&mut self, max_pixels: usize) {
+        self.max_pixels = max_pixels;
+    }
+
+    /// Decompress `nx` pixels from `input` into `output`, which must hold
+    /// exactly `nx` pixels.
+    ///
+    /// This is `fits_rdecomp`, `fits_rdecomp_short` or `fits_rdecomp_byte`
+    /// depending on the width of `T`.
+    pub fn decode<T: RiceSample>(
         &self,
         input: &[u8], /* input buffer			*/
         nx: usize,    /* number of output pixels	*/
         nblock: usize,
-        output: &mut [c_uint],
+        output: &mut [T],
     ) -> Result<(), DecodeError> /* coding block size		*/ {
-        /* int bsize;  */
+        self.decode_blocks(input, nx, nblock, output, None)
+    }
 
-        let mut k: i32;
-        let mut imax: usize;
+    /// As [`decode`](Self::decode), also returning how each block was coded.
+    /// The report matches the one from
+    /// [`RCEncoder::encode_with_stats`](crate::write::RCEncoder::encode_with_stats).
+    pub fn decode_with_stats<T: RiceSample>(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        output: &mut [T],
+    ) -> Result<Vec<BlockStats>, DecodeError> {
+        let mut stats = Vec::new();
+        self.decode_blocks(input, nx, nblock, output, Some(&mut stats))?;
+        Ok(stats)
+    }
 
-        let mut nzero: i32;
-        let mut fs: i32;
+    fn decode_blocks<T: RiceSample>(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        output: &mut [T],
+        stats: Option<&mut Vec<BlockStats>>,
+    ) -> Result<(), DecodeError> {
+        if nblock == 0 {
+            return Err(DecodeError::ZeroSizeInput);
+        }
+        if output.len() != nx {
+            (self.log_fn)("decompression error: output buffer not properly allocated");
+            return Err(DecodeError::NotProperlyAllocated);
+        }
+        output.fill(T::default());
 
-        let mut diff: u32;
+        /* first bsize bytes of input buffer contain the value of the first */
+        /* integer value, without any encoding */
 
-        assert_eq!(output.len(), nx);
-        output.fill(0);
+        let bsize = (T::BBITS / 8) as usize;
+        if input.len() < bsize {
+            (self.log_fn)("decompression error: input buffer not properly allocated");
+            return Err(DecodeError::NotProperlyAllocated);
+        }
 
-        /*
-         * Original size of each pixel (bsize, bytes) and coding block
-         * size (nblock, pixels)
-         * Could make bsize a parameter to allow more efficient
-         * compression of short & byte images.
-         */
-        /*    bsize = 4; */
+        let mut reader = BitReader::new(input, self.log_fn);
+        reader.start::<T>()?;
 
         /*
-         * From bsize derive:
-         * FSBITS = # bits required to store FS
-         * FSMAX = maximum value for FS
-         * BBITS = bits/pixel for direct coding
+         * Decode in blocks of nblock pixels
          */
+        match stats {
+            None => {
+                for block in output.chunks_mut(nblock) {
+                    reader.decode_block(block)?;
+                }
+            }
+            Some(stats) => {
+                let mut diff: Vec<u64> = vec![0; nblock];
+                for block in output.chunks_mut(nblock) {
+                    stats.push(reader.decode_block_with_stats(block, &mut diff)?);
+                }
+            }
+        }
+        if reader.position() < input.len() {
+            (self.log_fn)("decompression warning: unused bytes at end of compressed buffer");
+        }
 
-        /* move out of switch block, to tweak performance */
-        let fsbits: i32 = 5;
-        let fsmax: i32 = 25;
+        Ok(())
+    }
 
-        let bbits: i32 = 1 << fsbits;
+    /// Decompress a stream written by
+    /// [`RCEncoder::encode_nulls`](crate::write::RCEncoder::encode_nulls),
+    /// setting the pixels marked in `mask` back to `null`. The mask gives the
+    /// number of pixels, and `output` must hold exactly that many.
+    pub fn decode_nulls<T: RiceSample>(
+        &self,
+        input: &[u8],
+        nblock: usize,
+        mask: &NullMask,
+        null: T,
+        output: &mut [T],
+    ) -> Result<(), DecodeError> {
+        self.decode(input, mask.len(), nblock, output)?;
+        mask.restore(output, null);
+        Ok(())
+    }
 
-        /*
-         * Decode in blocks of nblock pixels
-         */
+    /// Check that `input` is a well formed stream of `nx` pixels of the given
+    /// width in blocks of `nblock`, without decoding the pixel values.
+    ///
+    /// Every block header and code is checked as [`decode`](Self::decode)
+    /// would. Returns the number of unused bytes after the end of the stream,
+    /// which is 0 for a stream as written by the encoder.
+    pub fn validate(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        width: SampleWidth,
+    ) -> Result<usize, DecodeError> {
+        match width {
+            SampleWidth::Byte => self.validate_as::<i8>(input, nx, nblock),
+            SampleWidth::Short => self.validate_as::<i16>(input, nx, nblock),
+            SampleWidth::Int => self.validate_as::<i32>(input, nx, nblock),
+            SampleWidth::Long => self.validate_as::<i64>(input, nx, nblock),
+        }
+    }
 
-        /* first 4 bytes of input buffer contain the value of the first */
-        /* 4 byte integer value, without any encoding */
+    fn validate_as<T: RiceSample>(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+    ) -> Result<usize, DecodeError> {
+        if nblock == 0 {
+            return Err(DecodeError::ZeroSizeInput);
+        }
 
-        if (input.len() < 4) {
-            (self.log_fn)("decompression error: input buffer not properly allocated");
-            return Err(DecodeError::NotProperlyAllocated);
+        let mut reader = BitReader::new(input, self.log_fn);
+        reader.start::<T>()?;
+        for start in (0..nx).step_by(nblock) {
+            reader.skip_block::<T>(nblock.min(nx - start))?;
         }
 
-        let mut lastpix: u32 = 0;
-        let mut bytevalue: u8 = input[0];
-        lastpix |= (bytevalue as u32) << 24;
-        bytevalue = input[1];
-        lastpix |= (bytevalue as u32) << 16;
-        bytevalue = input[2];
-        lastpix |= (bytevalue as u32) << 8;
-        bytevalue = input[3];
-        lastpix |= bytevalue as u32;
-
-        let mut c_current: usize = 4;
-
-        // cend = c + clen - 4;
-
-        let mut b: u32 = input[c_current] as u32; /* bit buffer			*/
-        c_current += 1;
-        let mut nbits: i32 = 8; /* number of bits remaining in b	*/
-
-        let mut i: usize = 0;
-        while i < nx {
-            /* get the FS value from first fsbits */
-            nbits -= fsbits;
-            while nbits < 0 {
-                b = (b << 8) | input[c_current] as u32;
-                c_current += 1;
-                nbits += 8;
-            }
-            fs = ((b >> nbits).wrapping_sub(1)) as i32;
+        Ok(input.len() - reader.position())
+    }
 
-            b &= (1 << nbits) - 1;
-            /* loop over the next block */
-            imax = i + nblock;
-            if imax > nx {
-                imax = nx;
-            }
-            if fs < 0 {
-                /* low-entropy case, all zero differences */
-                while i < imax {
-                    output[i] = lastpix;
-                    i += 1;
-                }
-            } else if fs == fsmax {
-                /* high-entropy case, directly coded pixel values */
-                while i < imax {
-                    k = bbits - nbits;
-                    diff = b.wrapping_shl(k as u32);
-                    k -= 8;
-                    while k >= 0 {
-                        b = input[c_current] as u32;
-                        c_current += 1;
-                        diff |= b << k;
-                        k -= 8
-                    }
-                    if nbits > 0 {
-                        b = input[c_current] as u32;
-                        c_current += 1;
-                        diff |= b >> (-k);
-                        b &= (1 << nbits) - 1;
-                    } else {
-                        b = 0;
-                    }
-                    /*
-                     * undo mapping and differencing
-                     * Note that some of these operations will overflow the
-                     * unsigned int arithmetic -- that's OK, it all works
-                     * out to give the right answers in the output file.
-                     */
-                    if (diff & 1) == 0 {
-                        diff >>= 1;
-                    } else {
-                        diff = !(diff >> 1);
-                    }
-                    output[i] = diff.wrapping_add(lastpix);
-                    lastpix = output[i];
-                    i += 1;
-                }
-            } else {
-                /* normal case, Rice coding */
-                while i < imax {
-                    /* count number of leading zeros */
-                    while b == 0 {
-                        nbits += 8;
-
-                        b = input[c_current] as u32;
-                        c_current += 1;
-                    }
-                    nzero = nbits - NONZERO_COUNT[b as usize];
-                    nbits -= nzero + 1;
-                    /* flip the leading one-bit */
-                    b ^= 1 << nbits;
-                    /* get the FS trailing bits */
-                    nbits -= fs;
-                    while nbits < 0 {
-                        b = (b << 8) | (input[c_current] as u32);
-
-                        c_current += 1;
-                        nbits += 8;
-                    }
-                    diff = ((nzero as u32) << fs) | (b >> nbits);
-                    b &= (1 << nbits) - 1;
-
-                    /* undo mapping and differencing */
-                    if (diff & 1) == 0 {
-                        diff >>= 1;
-                    } else {
-                        diff = !(diff >> 1);
-                    }
-                    output[i] = diff.wrapping_add(lastpix);
-                    lastpix = output[i];
-                    i += 1;
+    /// Decompress a framed stream written by
+    /// [`RCEncoder::encode_framed`](crate::write::RCEncoder::encode_framed),
+    /// taking the pixel count and block size from its header.
+    ///
+    /// Use [`FrameHeader::read`] first if the sample width is not known.
+    /// See [`set_max_pixels`](Self::set_max_pixels) for bounding the size of
+    /// the output.
+    pub fn decode_framed<T: RiceSample>(&self, input: &[u8]) -> Result<Vec<T>, DecodeError> {
+        let header = FrameHeader::read(input)?;
+        if header.width != T::WIDTH {
+            return Err(DecodeError::WrongSampleWidth {
+                expected: T::WIDTH,
+                found: header.width,
+            });
+        }
+        if header.nx > self.max_pixels {
+            (self.log_fn)("decompression error: pixel count over the limit");
+            return Err(DecodeError::TooManyPixels {
+                nx: header.nx,
+                max: self.max_pixels,
+            });
+        }
+        let payload = &input[frame::HEADER_LEN..];
+
+        let mut reader = BitReader::new(payload, self.log_fn);
+        reader.start::<T>()?;
+        let output = reader.decode_growing(header.nx, header.nblock)?;
+
+        let (coded, rest) = payload.split_at(reader.position());
+        let rest = if header.checksum {
+            let trailer = match rest.get(..frame::CHECKSUM_LEN) {
+                Some(trailer) => trailer,
+                None => {
+                    (self.log_fn)("decompression error: hit end of compressed byte stream");
+                    return Err(DecodeError::EndOfBuffer {
+                        pixel: header.nx,
+                        offset: input.len(),
+                    });
                 }
+            };
+            let mut crc = frame::Crc32::new();
+            crc.update(coded);
+            if crc.finish().to_be_bytes() != trailer {
+                (self.log_fn)("decompression error: checksum mismatch");
+                return Err(DecodeError::ChecksumMismatch);
             }
-            if c_current > input.len() {
-                (self.log_fn)("decompression error: hit end of compressed byte stream");
-                return Err(DecodeError::EndOfBuffer);
-            }
-        }
-        if c_current < input.len() {
+            &rest[frame::CHECKSUM_LEN..]
+        } else {
+            rest
+        };
+        if !rest.is_empty() {
             (self.log_fn)("decompression warning: unused bytes at end of compressed buffer");
         }
 
-        Ok(())
+        Ok(output)
     }
 
-    pub fn decode_short(
+    /// Decode `nx` pixels coded at `width` into a new vector of type `T`, as
+    /// [`decode_as`](Self::decode_as) does. For sizes taken from untrusted
+    /// headers: the output grows as pixels are actually decoded, so a stream
+    /// that ends early fails before much memory is used.
+    pub(crate) fn decode_vec_as<T: RiceSample>(
         &self,
-        input: &[u8], /* input buffer			*/
-        nx: usize,    /* number of output pixels	*/
+        input: &[u8],
+        nx: usize,
         nblock: usize,
-        output: &mut [c_ushort],
-    ) -> Result<(), DecodeError> /* coding block size		*/ {
-        /* int bsize;  */
+        width: SampleWidth,
+    ) -> Result<Vec<T>, DecodeError> {
+        match width {
+            _ if width == T::WIDTH => self.decode_vec(input, nx, nblock),
+            SampleWidth::Byte => Ok(sample::convert(&self.decode_vec::<u8>(input, nx, nblock)?)),
+            SampleWidth::Short => Ok(sample::convert(&self.decode_vec::<i16>(input, nx, nblock)?)),
+            SampleWidth::Int => Ok(sample::convert(&self.decode_vec::<i32>(input, nx, nblock)?)),
+            SampleWidth::Long => Ok(sample::convert(&self.decode_vec::<i64>(input, nx, nblock)?)),
+        }
+    }
 
-        let mut k: i32;
-        let mut imax: usize;
+    fn decode_vec<T: RiceSample>(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+    ) -> Result<Vec<T>, DecodeError> {
+        if nblock == 0 {
+            return Err(DecodeError::ZeroSizeInput);
+        }
 
-        let mut nzero: i32;
-        let mut fs: i32;
+        let mut reader = BitReader::new(input, self.log_fn);
+        reader.start::<T>()?;
+        let output = reader.decode_growing(nx, nblock)?;
+        if reader.position() < input.len() {
+            (self.log_fn)("decompression warning: unused bytes at end of compressed buffer");
+        }
+        Ok(output)
+    }
 
-        let mut diff: u32;
+    /// Decode a stream produced by [`RCEncoder::encode_unsigned`](crate::write::RCEncoder::encode_unsigned)
+    pub fn decode_unsigned(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        output: &mut [u32],
+    ) -> Result<(), DecodeError> {
+        self.decode(input, nx, nblock, output)
+    }
 
-        assert_eq!(output.len(), nx);
-        output.fill(0);
+    /// Decode a stream produced by [`RCEncoder::encode_short`](crate::write::RCEncoder::encode_short)
+    pub fn decode_short(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        output: &mut [i16],
+    ) -> Result<(), DecodeError> {
+        self.decode(input, nx, nblock, output)
+    }
 
-        /*
-         * Original size of each pixel (bsize, bytes) and coding block
-         * size (nblock, pixels)
-         * Could make bsize a parameter to allow more efficient
-         * compression of short & byte images.
-         */
-        /*    bsize = 2; */
+    /// Decode a stream produced by [`RCEncoder::encode_ushort`](crate::write::RCEncoder::encode_ushort)
+    pub fn decode_ushort(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        output: &mut [u16],
+    ) -> Result<(), DecodeError> {
+        self.decode(input, nx, nblock, output)
+    }
 
-        /*
-         * From bsize derive:
-         * FSBITS = # bits required to store FS
-         * FSMAX = maximum value for FS
-         * BBITS = bits/pixel for direct coding
-         */
+    /// Decode a stream produced by [`RCEncoder::encode_long`](crate::write::RCEncoder::encode_long)
+    pub fn decode_long(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        output: &mut [i64],
+    ) -> Result<(), DecodeError> {
+        self.decode(input, nx, nblock, output)
+    }
 
-        /* move out of switch block, to tweak performance */
-        let fsbits: i32 = 4;
-        let fsmax: i32 = 14;
+    /// Decode a stream produced by [`RCEncoder::encode_ulong`](crate::write::RCEncoder::encode_ulong)
+    pub fn decode_ulong(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        output: &mut [u64],
+    ) -> Result<(), DecodeError> {
+        self.decode(input, nx, nblock, output)
+    }
 
-        let bbits: i32 = 1 << fsbits;
+    /// Decode a stream coded at `width` into samples of type `T`, as written
+    /// by [`RCEncoder::encode_as`](crate::write::RCEncoder::encode_as) or
+    /// [`RCEncoder::encode_narrowest`](crate::write::RCEncoder::encode_narrowest).
+    /// Bytes are widened as unsigned and wider samples as signed.
+    pub fn decode_as<T: RiceSample>(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        width: SampleWidth,
+        output: &mut [T],
+    ) -> Result<(), DecodeError> {
+        match width {
+            _ if width == T::WIDTH => self.decode(input, nx, nblock, output),
+            SampleWidth::Byte => self.decode_via::<u8, T>(input, nx, nblock, output),
+            SampleWidth::Short => self.decode_via::<i16, T>(input, nx, nblock, output),
+            SampleWidth::Int => self.decode_via::<i32, T>(input, nx, nblock, output),
+            SampleWidth::Long => self.decode_via::<i64, T>(input, nx, nblock, output),
+        }
+    }
 
-        /*
-         * Decode in blocks of nblock pixels
-         */
+    fn decode_via<U: RiceSample, T: RiceSample>(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        output: &mut [T],
+    ) -> Result<(), DecodeError> {
+        if output.len() != nx {
+            (self.log_fn)("decompression error: output buffer not properly allocated");
+            return Err(DecodeError::NotProperlyAllocated);
+        }
+        let mut coded = vec![U::default(); nx];
+        self.decode(input, nx, nblock, &mut coded)?;
+        output.copy_from_slice(&sample::convert(&coded));
+        Ok(())
+    }
 
-        /* first 2 bytes of input buffer contain the value of the first */
-        /* 2 byte integer value, without any encoding */
+    /// Decode a stream produced by [`RCEncoder::encode_byte`](crate::write::RCEncoder::encode_byte)
+    pub fn decode_byte(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        output: &mut [i8],
+    ) -> Result<(), DecodeError> {
+        self.decode(input, nx, nblock, output)
+    }
 
-        let mut lastpix: u32 = 0;
-        let mut bytevalue: u8 = input[0];
-        lastpix |= (bytevalue as u32) << 8;
-        bytevalue = input[1];
-        lastpix |= bytevalue as u32;
+    /// Decode a stream produced by [`RCEncoder::encode_ubyte`](crate::write::RCEncoder::encode_ubyte)
+    pub fn decode_ubyte(
+        &self,
+        input: &[u8],
+        nx: usize,
+        nblock: usize,
+        output: &mut [u8],
+    ) -> Result<(), DecodeError> {
+        self.decode(input, nx, nblock, output)
+    }
+}
 
-        let mut c_current: usize = 2;
+/// Rice decoder that reads a compressed stream from any [`Read`] and hands out
+/// the pixels a block at a time.
+///
+/// Only one coding block of pixels is held at once, so arbitrarily large
+/// streams can be decoded with bounded memory. The reader is wrapped in a
+/// [`BufReader`], so there is no need to buffer it beforehand.
+///
+/// Reads interrupted by a signal are retried. After any other error the
+/// decoder is left part way through a block, so it is poisoned: every later
+/// call returns [`DecodeError::Poisoned`].
+pub struct RCStreamDecoder<R: Read, T: RiceSample> {
+    reader: BitReader<BufReader<R>>,
+    nx: usize,
+    nblock: usize,
+    block: Vec<T>, /* decoded pixels not yet handed out */
+    block_pos: usize,
+    error: Option<DecodeError>, /* held back while pixels before it are read */
+    poisoned: bool,
+}
 
-        // cend = c + clen - 2;
+impl<R: Read, T: RiceSample> RCStreamDecoder<R, T> {
+    /// Decoder for a stream of `nx` pixels coded in blocks of `nblock`
+    pub fn new(inner: R, nx: usize, nblock: usize) -> Self {
+        RCStreamDecoder {
+            reader: BitReader::new(BufReader::new(inner), log_noop),
+            nx,
+            nblock,
+            block: Vec::new(),
+            block_pos: 0,
+            error: None,
+            poisoned: false,
+        }
+    }
 
-        let mut b: u32 = input[c_current] as u32; /* bit buffer			*/
-        c_current += 1;
-        let mut nbits: i32 = 8; /* number of bits remaining in b	*/
+    pub fn set_log_fn(&mut self, log_fn: fn(&str)) {
+        self.reader.log_fn = log_fn;
+    }
 
-        let mut i: usize = 0;
-        while i < nx {
-            /* get the FS value from first fsbits */
-            nbits -= fsbits;
-            while nbits < 0 {
-                b = (b << 8) | input[c_current] as u32;
-                c_current += 1;
-                nbits += 8;
-            }
-            fs = ((b >> nbits).wrapping_sub(1)) as i32;
+    /// Number of pixels that have not been handed out yet
+    pub fn remaining(&self) -> usize {
+        self.nx - self.reader.pixel + (self.block.len() - self.block_pos)
+    }
+
+    /// Decode pixels into `output`, returning how many were written. Zero is
+    /// returned once all `nx` pixels have been read.
+    ///
+    /// If decoding fails after some pixels were written, their count is
+    /// returned and the error comes from the next call. The stream cannot be
+    /// resumed after an error.
+    pub fn read_pixels(&mut self, output: &mut [T]) -> Result<usize, DecodeError> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        if self.poisoned {
+            return Err(DecodeError::Poisoned);
+        }
 
-            b &= (1 << nbits) - 1;
-            /* loop over the next block */
-            imax = i + nblock;
-            if imax > nx {
-                imax = nx;
+        let mut written = 0;
+        let result = self.read_blocks(output, &mut written);
+        match result {
+            Ok(()) => Ok(written),
+            Err(err) => {
+                self.poisoned = true;
+                if written == 0 {
+                    return Err(err);
+                }
+                self.error = Some(err);
+                Ok(written)
             }
-            if fs < 0 {
-                /* low-entropy case, all zero differences */
-                while i < imax {
-                    output[i] = lastpix as c_ushort;
-                    i += 1;
+        }
+    }
+
+    /// Fill `output` from `written` on, counting the pixels in `written`
+    fn read_blocks(&mut self, output: &mut [T], written: &mut usize) -> Result<(), DecodeError> {
+        if self.nblock == 0 {
+            return Err(DecodeError::ZeroSizeInput);
+        }
+        while *written < output.len() {
+            let at = *written;
+            if self.block_pos == self.block.len() {
+                if self.reader.pixel == self.nx {
+                    break;
                 }
-            } else if fs == fsmax {
-                /* high-entropy case, directly coded pixel values */
-                while i < imax {
-                    k = bbits - nbits;
-                    diff = b.wrapping_shl(k as u32);
-                    k -= 8;
-                    while k >= 0 {
-                        b = input[c_current] as u32;
-                        c_current += 1;
-                        diff |= b << k;
-                        k -= 8
-                    }
-                    if nbits > 0 {
-                        b = input[c_current] as u32;
-                        c_current += 1;
-                        diff |= b >> (-k);
-                        b &= (1 << nbits) - 1;
-                    } else {
-                        b = 0;
-                    }
-                    /*
-                     * undo mapping and differencing
-                     * Note that some of these operations will overflow the
-                     * unsigned int arithmetic -- that's OK, it all works
-                     * out to give the right answers in the output file.
-                     */
-                    if (diff & 1) == 0 {
-                        diff >>= 1;
-                    } else {
-                        diff = !(diff >> 1);
-                    }
-                    output[i] = diff.wrapping_add(lastpix) as c_ushort;
-                    lastpix = output[i] as u32;
-                    i += 1;
+                if self.reader.pixel == 0 {
+                    self.reader.start::<T>()?;
                 }
-            } else {
-                /* normal case, Rice coding */
-                while i < imax {
-                    /* count number of leading zeros */
-                    while b == 0 {
-                        nbits += 8;
-
-                        b = input[c_current] as u32;
-                        c_current += 1;
-                    }
-                    nzero = nbits - NONZERO_COUNT[b as usize];
-                    nbits -= nzero + 1;
-                    /* flip the leading one-bit */
-                    b ^= 1 << nbits;
-                    /* get the FS trailing bits */
-                    nbits -= fs;
-                    while nbits < 0 {
-                        b = (b << 8) | (input[c_current] as u32);
-
-                        c_current += 1;
-                        nbits += 8;
-                    }
-                    diff = ((nzero as u32) << fs) | (b >> nbits);
-                    b &= (1 << nbits) - 1;
-
-                    /* undo mapping and differencing */
-                    if (diff & 1) == 0 {
-                        diff >>= 1;
-                    } else {
-                        diff = !(diff >> 1);
-                    }
-                    output[i] = diff.wrapping_add(lastpix) as c_ushort;
-                    lastpix = output[i] as u32;
-                    i += 1;
+
+                let len = self.nblock.min(self.nx - self.reader.pixel);
+                if output.len() - at >= len {
+                    /* whole block fits, decode it in place */
+                    self.reader.decode_block(&mut output[at..at + len])?;
+                    *written += len;
+                    continue;
                 }
+                self.block.resize(len, T::default());
+                self.block_pos = 0;
+                self.reader.decode_block(&mut self.block)?;
             }
-            if c_current > input.len() {
-                (self.log_fn)("decompression error: hit end of compressed byte stream");
-                return Err(DecodeError::EndOfBuffer);
-            }
-        }
-        if c_current < input.len() {
-            (self.log_fn)("decompression warning: unused bytes at end of compressed buffer");
+
+            let n = (self.block.len() - self.block_pos).min(output.len() - at);
+            output[at..at + n].copy_from_slice(&self.block[self.block_pos..self.block_pos + n]);
+            self.block_pos += n;
+            *written += n;
         }
 
         Ok(())
     }
 
-    pub fn decode_byte(
-        &self,
-        input: &[u8], /* input buffer			*/
-        nx: usize,    /* number of output pixels	*/
-        nblock: usize,
-        output: &mut [c_uchar],
-    ) -> Result<(), DecodeError> /* coding block size		*/ {
-        /* int bsize;  */
+    /// The underlying reader. Compressed bytes that were buffered but not yet
+    /// decoded are lost.
+    pub fn into_inner(self) -> R {
+        self.reader.inner.into_inner()
+    }
+}
+
+impl<R: Read, T: RiceSample> Iterator for RCStreamDecoder<R, T> {
+    type Item = Result<T, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.poisoned && self.error.is_none() {
+            return None;
+        }
 
-        let mut k: i32;
-        let mut imax: usize;
+        let mut pixel = [T::default()];
+        match self.read_pixels(&mut pixel) {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(pixel[0])),
+            Err(err) => Some(Err(err)),
+        }
+    }
 
-        let mut nzero: i32;
-        let mut fs: i32;
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.poisoned {
+            (0, Some(self.error.is_some() as usize))
+        } else {
+            (0, Some(self.remaining()))
+        }
+    }
+}
+
+/// Decoding state carried from one block to the next: the bit reservoir, the
+/// last pixel value and the position in the stream.
+///
+/// The reservoir holds up to 64 bits with the next bit in the most significant
+/// position and is refilled a word at a time while at least 8 bytes remain,
+/// falling back to single bytes near the end of the input. Bits below the
+/// `avail` valid ones are either zero or already the true upcoming bits, so
+/// OR-ing the next word in over them is harmless.
+struct BitReader<R: BufRead> {
+    inner: R,
+    log_fn: fn(&str),
+    bits: u64,     /* bit reservoir */
+    avail: u32,    /* number of valid bits at the top of the reservoir */
+    lastpix: u64,  /* value of the previous pixel */
+    offset: usize, /* bytes moved from inner into the reservoir */
+    pixel: usize,  /* index of the next pixel to decode */
+}
 
-        let mut diff: u32;
+impl<R: BufRead> BitReader<R> {
+    fn new(inner: R, log_fn: fn(&str)) -> Self {
+        BitReader {
+            inner,
+            log_fn,
+            bits: 0,
+            avail: 0,
+            lastpix: 0,
+            offset: 0,
+            pixel: 0,
+        }
+    }
 
-        assert_eq!(output.len(), nx);
-        output.fill(0);
+    /// Number of input bytes the decoded pixels have used, counting a
+    /// partially used byte
+    fn position(&self) -> usize {
+        self.offset - (self.avail / 8) as usize
+    }
 
-        /*
-         * Original size of each pixel (bsize, bytes) and coding block
-         * size (nblock, pixels)
-         * Could make bsize a parameter to allow more efficient
-         * compression of short & byte images.
-         */
-        /*    bsize = 1; */
+    /// Number of bits of the input the decoded pixels have used
+    fn bit_position(&self) -> u64 {
+        self.offset as u64 * 8 - self.avail as u64
+    }
 
+    /// Read the raw first pixel value
+    fn start<T: RiceSample>(&mut self) -> Result<(), DecodeError> {
+        self.lastpix = self.read_bits(T::BBITS as u32)?;
+        Ok(())
+    }
+
+    /// Decode one coding block, which is `output.len()` pixels long, returning
+    /// its FS value (-1 for a zero block)
+    fn decode_block<T: RiceSample>(&mut self, output: &mut [T]) -> Result<i32, DecodeError> {
+        let fs = self.block_header::<T>()?;
+        self.decode_pixels(fs, output)?;
+        Ok(fs)
+    }
+
+    /// Decode `nx` pixels in blocks of `nblock` into a new vector, growing it
+    /// a bounded chunk at a time as the pixels are decoded
+    fn decode_growing<T: RiceSample>(
+        &mut self,
+        nx: usize,
+        nblock: usize,
+    ) -> Result<Vec<T>, DecodeError> {
+        let mut output = Vec::new();
+        while output.len() < nx {
+            let block_end = output.len() + nblock.min(nx - output.len());
+            let fs = self.block_header::<T>()?;
+            while output.len() < block_end {
+                let start = output.len();
+                let len = DECODE_CHUNK.min(block_end - start);
+                output.resize(start + len, T::default());
+                self.decode_pixels(fs, &mut output[start..])?;
+            }
+        }
+        Ok(output)
+    }
+
+    /// Read the split parameter `fs` that starts a block: -1 for all zero
+    /// differences and FSMAX for directly coded pixels
+    fn block_header<T: RiceSample>(&mut self) -> Result<i32, DecodeError> {
         /*
          * From bsize derive:
          * FSBITS = # bits required to store FS
          * FSMAX = maximum value for FS
-         * BBITS = bits/pixel for direct coding
          */
 
-        /* move out of switch block, to tweak performance */
-        let fsbits: i32 = 3;
-        let fsmax: i32 = 6;
+        /* get the FS value from first fsbits */
+        let fs = (self.read_bits(T::FSBITS as u32)? as i32) - 1;
+        if fs > T::FSMAX {
+            (self.log_fn)("decompression error: invalid block header");
+            return Err(self.corrupt());
+        }
+        Ok(fs)
+    }
 
-        let bbits: i32 = 1 << fsbits;
+    /// Decode the next `output.len()` pixels of a block with split parameter
+    /// `fs`. A block may be decoded in several pieces.
+    fn decode_pixels<T: RiceSample>(
+        &mut self,
+        fs: i32,
+        output: &mut [T],
+    ) -> Result<(), DecodeError> {
+        let mut diff: u64;
+        let bbits = T::BBITS as u32;
+
+        /* loop over the next block */
+        if fs < 0 {
+            /* low-entropy case, all zero differences */
+            output.fill(T::from_bits(self.lastpix));
+            self.pixel += output.len();
+        } else if fs == T::FSMAX {
+            /* high-entropy case, directly coded pixel values */
+            for pixel in output.iter_mut() {
+                diff = self.read_bits(bbits)?;
+                /*
+                 * undo mapping and differencing
+                 * Note that some of these operations will overflow the
+                 * unsigned int arithmetic -- that's OK, it all works
+                 * out to give the right answers in the output file.
+                 */
+                if (diff & 1) == 0 {
+                    diff >>= 1;
+                } else {
+                    diff = !(diff >> 1);
+                }
+                self.lastpix = diff.wrapping_add(self.lastpix);
+                *pixel = T::from_bits(self.lastpix);
+                self.pixel += 1;
+            }
+        } else {
+            /* normal case, Rice coding */
+            let fs = fs as u32;
+            for pixel in output.iter_mut() {
+                diff = self.read_rice(fs)?;
+
+                /* undo mapping and differencing */
+                if (diff & 1) == 0 {
+                    diff >>= 1;
+                } else {
+                    diff = !(diff >> 1);
+                }
+                self.lastpix = diff.wrapping_add(self.lastpix);
+                *pixel = T::from_bits(self.lastpix);
+                self.pixel += 1;
+            }
+        }
 
-        /*
-         * Decode in blocks of nblock pixels
-         */
+        Ok(())
+    }
 
-        /* first byte of input buffer contain the value of the first */
-        /* byte integer value, without any encoding */
+    /// Check one coding block of `len` pixels and step over it without
+    /// reconstructing the pixel values
+    fn skip_block<T: RiceSample>(&mut self, len: usize) -> Result<(), DecodeError> {
+        let fs = (self.read_bits(T::FSBITS as u32)? as i32) - 1;
+        if fs > T::FSMAX {
+            (self.log_fn)("decompression error: invalid block header");
+            return Err(self.corrupt());
+        }
 
-        let mut lastpix: u32 = input[0] as u32;
+        if fs == T::FSMAX {
+            for _ in 0..len {
+                self.read_bits(T::BBITS as u32)?;
+                self.pixel += 1;
+            }
+        } else if fs >= 0 {
+            for _ in 0..len {
+                self.read_rice(fs as u32)?;
+                self.pixel += 1;
+            }
+        } else {
+            self.pixel += len;
+        }
+        Ok(())
+    }
 
-        let mut c_current: usize = 1;
+    /// Read one Rice code with split parameter `fs`, returning the mapped
+    /// difference
+    #[inline]
+    fn read_rice(&mut self, fs: u32) -> Result<u64, DecodeError> {
+        /* count number of leading zeros and skip the one-bit after them */
+        let mut nzero: u64 = 0;
+        loop {
+            let lz = self.bits.leading_zeros();
+            if lz < self.avail {
+                self.consume(lz + 1);
+                nzero += lz as u64;
+                break;
+            }
+            nzero += self.avail as u64;
+            self.consume(self.avail);
+            self.fill(1)?;
+        }
+        if nzero.leading_zeros() < fs {
+            (self.log_fn)("decompression error: run of zeros too long");
+            return Err(self.corrupt());
+        }
+        /* get the FS trailing bits */
+        Ok((nzero << fs) | self.read_bits(fs)?)
+    }
 
-        // cend = c + clen - 2;
+    /// Decode one coding block and report how it was coded. `diff` is scratch
+    /// space of at least `output.len()`.
+    fn decode_block_with_stats<T: RiceSample>(
+        &mut self,
+        output: &mut [T],
+        diff: &mut [u64],
+    ) -> Result<BlockStats, DecodeError> {
+        let lastpix = T::from_bits(self.lastpix);
+        let start = self.bit_position();
+        let fs = self.decode_block(output)?;
+        let bits = self.bit_position() - start;
+
+        let kind = if fs < 0 {
+            BlockKind::Zero
+        } else if fs == T::FSMAX {
+            BlockKind::Raw
+        } else {
+            BlockKind::Rice
+        };
+        Ok(BlockStats {
+            pixels: output.len(),
+            kind,
+            fs: fs.max(0) as u32,
+            bits,
+            pixelsum: T::map_block(output, lastpix, &mut diff[..output.len()]),
+        })
+    }
 
-        let mut b: u32 = input[c_current] as u32; /* bit buffer			*/
-        c_current += 1;
-        let mut nbits: i32 = 8; /* number of bits remaining in b	*/
+    /// Take the next `n` bits of the stream (n <= 64)
+    #[inline]
+    fn read_bits(&mut self, n: u32) -> Result<u64, DecodeError> {
+        if n > 32 {
+            /* 64-bit values come in as two 32-bit halves, high half first */
+            let high = self.read_bits(n - 32)?;
+            return Ok((high << 32) | self.read_bits(32)?);
+        }
+        self.fill(n)?;
+        let value = self.bits.checked_shr(64 - n).unwrap_or(0);
+        self.consume(n);
+        Ok(value)
+    }
 
-        let mut i: usize = 0;
-        while i < nx {
-            /* get the FS value from first fsbits */
-            nbits -= fsbits;
-            while nbits < 0 {
-                b = (b << 8) | input[c_current] as u32;
-                c_current += 1;
-                nbits += 8;
-            }
-            fs = ((b >> nbits).wrapping_sub(1)) as i32;
+    /// Drop `n` bits from the top of the reservoir (n <= avail)
+    #[inline]
+    fn consume(&mut self, n: u32) {
+        self.bits = self.bits.checked_shl(n).unwrap_or(0);
+        self.avail -= n;
+    }
 
-            b &= (1 << nbits) - 1;
-            /* loop over the next block */
-            imax = i + nblock;
-            if imax > nx {
-                imax = nx;
-            }
-            if fs < 0 {
-                /* low-entropy case, all zero differences */
-                while i < imax {
-                    output[i] = lastpix as c_uchar;
-                    i += 1;
-                }
-            } else if fs == fsmax {
-                /* high-entropy case, directly coded pixel values */
-                while i < imax {
-                    k = bbits - nbits;
-                    diff = b.wrapping_shl(k as u32);
-                    k -= 8;
-                    while k >= 0 {
-                        b = input[c_current] as u32;
-                        c_current += 1;
-                        diff |= b << k;
-                        k -= 8
-                    }
-                    if nbits > 0 {
-                        b = input[c_current] as u32;
-                        c_current += 1;
-                        diff |= b >> (-k);
-                        b &= (1 << nbits) - 1;
-                    } else {
-                        b = 0;
-                    }
-                    /*
-                     * undo mapping and differencing
-                     * Note that some of these operations will overflow the
-                     * unsigned int arithmetic -- that's OK, it all works
-                     * out to give the right answers in the output file.
-                     */
-                    if (diff & 1) == 0 {
-                        diff >>= 1;
-                    } else {
-                        diff = !(diff >> 1);
-                    }
-                    output[i] = diff.wrapping_add(lastpix) as c_uchar;
-                    lastpix = output[i] as u32;
-                    i += 1;
-                }
-            } else {
-                /* normal case, Rice coding */
-                while i < imax {
-                    /* count number of leading zeros */
-                    while b == 0 {
-                        nbits += 8;
-
-                        b = input[c_current] as u32;
-                        c_current += 1;
-                    }
-                    nzero = nbits - NONZERO_COUNT[b as usize];
-                    nbits -= nzero + 1;
-                    /* flip the leading one-bit */
-                    b ^= 1 << nbits;
-                    /* get the FS trailing bits */
-                    nbits -= fs;
-                    while nbits < 0 {
-                        b = (b << 8) | (input[c_current] as u32);
-
-                        c_current += 1;
-                        nbits += 8;
-                    }
-                    diff = ((nzero as u32) << fs) | (b >> nbits);
-                    b &= (1 << nbits) - 1;
-
-                    /* undo mapping and differencing */
-                    if (diff & 1) == 0 {
-                        diff >>= 1;
-                    } else {
-                        diff = !(diff >> 1);
-                    }
-                    output[i] = diff.wrapping_add(lastpix) as c_uchar;
-                    lastpix = output[i] as u32;
-                    i += 1;
-                }
-            }
-            if c_current > input.len() {
-                (self.log_fn)("decompression error: hit end of compressed byte stream");
-                return Err(DecodeError::EndOfBuffer);
-            }
+    /// Make sure at least `n` bits (n <= 57) are in the reservoir, reporting
+    /// the pixel being decoded and the byte offset if the stream has run out.
+    #[inline]
+    fn fill(&mut self, n: u32) -> Result<(), DecodeError> {
+        if self.avail >= n {
+            return Ok(());
         }
-        if c_current < input.len() {
-            (self.log_fn)("decompression warning: unused bytes at end of compressed buffer");
+        self.refill()?;
+        if self.avail < n {
+            (self.log_fn)("decompression error: hit end of compressed byte stream");
+            return Err(DecodeError::EndOfBuffer {
+                pixel: self.pixel,
+                offset: self.offset,
+            });
         }
+        Ok(())
+    }
 
+    /// Top up the reservoir to at least 57 bits, or as far as the input goes
+    fn refill(&mut self) -> Result<(), DecodeError> {
+        let buf = self.fill_buf()?;
+        if let Some(word) = buf.get(..8) {
+            /* whole bytes that fit, the rest of the word is seen again next time */
+            let word = u64::from_be_bytes(word.try_into().unwrap());
+            let nbytes = (64 - self.avail) / 8;
+            self.bits |= word.checked_shr(self.avail).unwrap_or(0);
+            self.avail += nbytes * 8;
+            self.inner.consume(nbytes as usize);
+            self.offset += nbytes as usize;
+            return Ok(());
+        }
+
+        /* near the end of the input, one byte at a time */
+        while self.avail <= 56 {
+            let byte = match self.fill_buf()?.first() {
+                Some(&byte) => byte,
+                None => break,
+            };
+            self.inner.consume(1);
+            self.offset += 1;
+            self.bits |= (byte as u64) << (56 - self.avail);
+            self.avail += 8;
+        }
         Ok(())
     }
+
+    /// The buffered input, retrying reads interrupted by a signal as
+    /// `read_exact` does
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while let Err(err) = self.inner.fill_buf() {
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        /* hands back what the call above buffered */
+        self.inner.fill_buf()
+    }
+
+    fn corrupt(&self) -> DecodeError {
+        DecodeError::CorruptStream {
+            pixel: self.pixel,
+            offset: self.position(),
+        }
+    }
 }
@@ -0,0 +1,366 @@
+use crate::simd;
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for i8 {}
+    impl Sealed for u8 {}
+    impl Sealed for i16 {}
+    impl Sealed for u16 {}
+    impl Sealed for i32 {}
+    impl Sealed for u32 {}
+    impl Sealed for i64 {}
+    impl Sealed for u64 {}
+}
+
+/// Width of the samples in a stream, which selects its wire format
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SampleWidth {
+    Byte,
+    Short,
+    Int,
+    /// 64-bit samples, an extension beyond the CFITSIO wire format
+    Long,
+}
+
+impl SampleWidth {
+    /// Width for a number of bytes per pixel (the FITS `BYTEPIX` parameter)
+    pub fn from_bytepix(bytepix: usize) -> Option<SampleWidth> {
+        match bytepix {
+            1 => Some(SampleWidth::Byte),
+            2 => Some(SampleWidth::Short),
+            4 => Some(SampleWidth::Int),
+            8 => Some(SampleWidth::Long),
+            _ => None,
+        }
+    }
+
+    /// Number of bytes per pixel
+    pub fn bytepix(self) -> usize {
+        match self {
+            SampleWidth::Byte => 1,
+            SampleWidth::Short => 2,
+            SampleWidth::Int => 4,
+            SampleWidth::Long => 8,
+        }
+    }
+
+    /// Narrowest width that reproduces every value of `input` when coded at
+    /// that width and widened back as CFITSIO does: bytes as unsigned, wider
+    /// samples as signed. Never wider than the width of `T`.
+    ///
+    /// Coding the 32-bit values of a tile that only spans 0 to 255 as bytes,
+    /// say, gives a shorter stream than coding them at full width.
+    pub fn narrowest<T: RiceSample + PartialEq>(input: &[T]) -> SampleWidth {
+        let mut width = SampleWidth::Byte;
+        for &value in input {
+            while width != T::WIDTH && !width.holds(value) {
+                width = match width {
+                    SampleWidth::Byte => SampleWidth::Short,
+                    SampleWidth::Short => SampleWidth::Int,
+                    _ => SampleWidth::Long,
+                };
+            }
+            if width == T::WIDTH {
+                break;
+            }
+        }
+        width
+    }
+
+    /// `value` survives narrowing to this width and widening back
+    fn holds<T: RiceSample + PartialEq>(self, value: T) -> bool {
+        let bits = value.to_bits();
+        let narrowed = match self {
+            SampleWidth::Byte => bits as u8 as u64,
+            SampleWidth::Short => bits as i16 as u64,
+            SampleWidth::Int => bits as i32 as u64,
+            SampleWidth::Long => bits,
+        };
+        T::from_bits(narrowed) == value
+    }
+
+    pub(crate) fn fsbits(self) -> i32 {
+        match self {
+            SampleWidth::Byte => i8::FSBITS,
+            SampleWidth::Short => i16::FSBITS,
+            SampleWidth::Int => i32::FSBITS,
+            SampleWidth::Long => i64::FSBITS,
+        }
+    }
+
+    pub(crate) fn fsmax(self) -> i32 {
+        match self {
+            SampleWidth::Byte => i8::FSMAX,
+            SampleWidth::Short => i16::FSMAX,
+            SampleWidth::Int => i32::FSMAX,
+            SampleWidth::Long => i64::FSMAX,
+        }
+    }
+
+    pub(crate) fn bbits(self) -> i32 {
+        1 << self.fsbits()
+    }
+}
+
+/// Convert samples to another width through their raw bits: narrowing keeps
+/// the low bits, widening sign-extends signed types and zero-extends unsigned
+/// ones.
+pub(crate) fn convert<T: RiceSample, U: RiceSample>(input: &[T]) -> Vec<U> {
+    input
+        .iter()
+        .map(|&value| U::from_bits(value.to_bits()))
+        .collect()
+}
+
+/// A pixel type that can be Rice coded.
+///
+/// The trait is sealed: the constants below define the wire format for each
+/// sample width and must match what CFITSIO uses for 8, 16 and 32-bit data.
+pub trait RiceSample: Copy + Default + private::Sealed {
+    /// # bits required to store FS
+    const FSBITS: i32;
+    /// maximum value for FS
+    const FSMAX: i32;
+    /// bits/pixel for direct coding, also the width of the first raw value
+    const BBITS: i32 = 1 << Self::FSBITS;
+    /// Width of the wire format for this type
+    const WIDTH: SampleWidth;
+
+    /// Pixel value as raw bits, for writing the first value of the stream
+    fn to_bits(self) -> u64;
+
+    /// Pixel value from the low bits of `bits`
+    fn from_bits(bits: u64) -> Self;
+
+    /// Difference from the previous pixel, mapped to a non-negative value
+    fn map_diff(self, lastpix: Self) -> u64;
+
+    /// Mapped differences of a block of pixels following `lastpix`, written to
+    /// `diff`, and their sum. Vectorised for 16 and 32-bit samples.
+    #[doc(hidden)]
+    #[inline]
+    fn map_block(block: &[Self], lastpix: Self, diff: &mut [u64]) -> u128 {
+        simd::map_block_scalar(block, lastpix, diff)
+    }
+}
+
+impl RiceSample for i32 {
+    const FSBITS: i32 = 5;
+    const FSMAX: i32 = 25;
+    const WIDTH: SampleWidth = SampleWidth::Int;
+
+    #[inline]
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        bits as i32
+    }
+
+    #[inline]
+    fn map_diff(self, lastpix: Self) -> u64 {
+        let pdiff = self.wrapping_sub(lastpix);
+        (if pdiff < 0 { !(pdiff << 1) } else { pdiff << 1 }) as u32 as u64 // ! is bitwise complement
+    }
+
+    #[inline]
+    fn map_block(block: &[Self], lastpix: Self, diff: &mut [u64]) -> u128 {
+        if block.len() > simd::MAX_U64_BLOCK {
+            return simd::map_block_scalar(block, lastpix, diff);
+        }
+        simd::map_diffs_i32(block, lastpix, diff) as u128
+    }
+}
+
+impl RiceSample for i16 {
+    const FSBITS: i32 = 4;
+    const FSMAX: i32 = 14;
+    const WIDTH: SampleWidth = SampleWidth::Short;
+
+    #[inline]
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        bits as i16
+    }
+
+    #[inline]
+    fn map_diff(self, lastpix: Self) -> u64 {
+        /* the shift is done on the difference promoted to int, as in C */
+        let pdiff = self.wrapping_sub(lastpix) as i32;
+        (if pdiff < 0 { !(pdiff << 1) } else { pdiff << 1 }) as u32 as u64 // ! is bitwise complement
+    }
+
+    #[inline]
+    fn map_block(block: &[Self], lastpix: Self, diff: &mut [u64]) -> u128 {
+        if block.len() > simd::MAX_U64_BLOCK {
+            return simd::map_block_scalar(block, lastpix, diff);
+        }
+        simd::map_diffs_i16(block, lastpix, diff) as u128
+    }
+}
+
+impl RiceSample for i8 {
+    const FSBITS: i32 = 3;
+    const FSMAX: i32 = 6;
+    const WIDTH: SampleWidth = SampleWidth::Byte;
+
+    #[inline]
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        bits as i8
+    }
+
+    #[inline]
+    fn map_diff(self, lastpix: Self) -> u64 {
+        /* the shift is done on the difference promoted to int, as in C */
+        let pdiff = self.wrapping_sub(lastpix) as i32;
+        (if pdiff < 0 { !(pdiff << 1) } else { pdiff << 1 }) as u32 as u64 // ! is bitwise complement
+    }
+}
+
+/// 64-bit samples are an extension beyond the CFITSIO wire format, which stops
+/// at 32 bits: the FS field is widened to 6 bits and directly coded blocks
+/// hold 64-bit values. FSMAX keeps FS + 8 within the 64-bit bit buffers.
+impl RiceSample for i64 {
+    const FSBITS: i32 = 6;
+    const FSMAX: i32 = 57;
+    const WIDTH: SampleWidth = SampleWidth::Long;
+
+    #[inline]
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        bits as i64
+    }
+
+    #[inline]
+    fn map_diff(self, lastpix: Self) -> u64 {
+        let pdiff = self.wrapping_sub(lastpix);
+        (if pdiff < 0 { !(pdiff << 1) } else { pdiff << 1 }) as u64 // ! is bitwise complement
+    }
+}
+
+/*
+ * Unsigned samples are coded exactly like the signed type of the same width,
+ * so a stream can be decoded into either.
+ */
+
+impl RiceSample for u32 {
+    const FSBITS: i32 = i32::FSBITS;
+    const FSMAX: i32 = i32::FSMAX;
+    const WIDTH: SampleWidth = SampleWidth::Int;
+
+    #[inline]
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        bits as u32
+    }
+
+    #[inline]
+    fn map_diff(self, lastpix: Self) -> u64 {
+        (self as i32).map_diff(lastpix as i32)
+    }
+
+    #[inline]
+    fn map_block(block: &[Self], lastpix: Self, diff: &mut [u64]) -> u128 {
+        /* same bits as the signed type, which maps them identically */
+        // SAFETY: u32 and i32 have the same size and alignment and every bit
+        // pattern is valid for both, so the same pointer and length describe
+        // the same memory as a valid `&[i32]` borrowed for the same lifetime.
+        let block =
+            unsafe { std::slice::from_raw_parts(block.as_ptr() as *const i32, block.len()) };
+        i32::map_block(block, lastpix as i32, diff)
+    }
+}
+
+impl RiceSample for u16 {
+    const FSBITS: i32 = i16::FSBITS;
+    const FSMAX: i32 = i16::FSMAX;
+    const WIDTH: SampleWidth = SampleWidth::Short;
+
+    #[inline]
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        bits as u16
+    }
+
+    #[inline]
+    fn map_diff(self, lastpix: Self) -> u64 {
+        (self as i16).map_diff(lastpix as i16)
+    }
+
+    #[inline]
+    fn map_block(block: &[Self], lastpix: Self, diff: &mut [u64]) -> u128 {
+        /* same bits as the signed type, which maps them identically */
+        // SAFETY: u16 and i16 have the same size and alignment and every bit
+        // pattern is valid for both, so the same pointer and length describe
+        // the same memory as a valid `&[i16]` borrowed for the same lifetime.
+        let block =
+            unsafe { std::slice::from_raw_parts(block.as_ptr() as *const i16, block.len()) };
+        i16::map_block(block, lastpix as i16, diff)
+    }
+}
+
+impl RiceSample for u8 {
+    const FSBITS: i32 = i8::FSBITS;
+    const FSMAX: i32 = i8::FSMAX;
+    const WIDTH: SampleWidth = SampleWidth::Byte;
+
+    #[inline]
+    fn to_bits(self) -> u64 {
+        self as u64
+    }
+
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        bits as u8
+    }
+
+    #[inline]
+    fn map_diff(self, lastpix: Self) -> u64 {
+        (self as i8).map_diff(lastpix as i8)
+    }
+}
+
+impl RiceSample for u64 {
+    const FSBITS: i32 = i64::FSBITS;
+    const FSMAX: i32 = i64::FSMAX;
+    const WIDTH: SampleWidth = SampleWidth::Long;
+
+    #[inline]
+    fn to_bits(self) -> u64 {
+        self
+    }
+
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        bits
+    }
+
+    #[inline]
+    fn map_diff(self, lastpix: Self) -> u64 {
+        (self as i64).map_diff(lastpix as i64)
+    }
+}
@@ -0,0 +1,389 @@
+//! Vectorised pre-pass for the encoder: differences of adjacent pixels, mapped
+//! to non-negative values, and their sum.
+//!
+//! The scalar loop sums the mapped differences in a `u128`, which cannot
+//! overflow even for 64-bit samples. The kernels here sum in a `u64` instead,
+//! which is exact for 32-bit and narrower samples as long as the block has at
+//! most [`MAX_U64_BLOCK`] pixels; longer blocks use the scalar loop.
+//!
+//! Kernels are chosen at runtime: AVX2 if the CPU has it, else SSE2 on x86_64
+//! and NEON on aarch64, both of which are part of the baseline. Other targets
+//! use a portable loop.
+
+use crate::sample::RiceSample;
+
+/// Longest block whose sum of mapped 32-bit differences fits in a `u64`
+pub(crate) const MAX_U64_BLOCK: usize = u32::MAX as usize;
+
+/// The encoder's scalar loop
+pub(crate) fn map_block_scalar<T: RiceSample>(block: &[T], lastpix: T, diff: &mut [u64]) -> u128 {
+    let mut lastpix = lastpix;
+    let mut pixelsum = 0;
+    for (diff_item, &nextpix) in diff.iter_mut().zip(block) {
+        *diff_item = nextpix.map_diff(lastpix);
+        pixelsum += *diff_item as u128;
+        lastpix = nextpix;
+    }
+    pixelsum
+}
+
+/// Map the pixels of `block` from `start` (at least 1) on, summing exactly
+fn map_tail<T: RiceSample>(block: &[T], start: usize, diff: &mut [u64]) -> u64 {
+    let mut sum = 0;
+    for i in start..block.len() {
+        diff[i] = block[i].map_diff(block[i - 1]);
+        sum += diff[i];
+    }
+    sum
+}
+
+/// Portable version of the kernels below
+#[cfg(any(test, not(any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub(crate) fn map_diffs_portable<T: RiceSample>(block: &[T], lastpix: T, diff: &mut [u64]) -> u64 {
+    if block.is_empty() {
+        return 0;
+    }
+    diff[0] = block[0].map_diff(lastpix);
+    diff[0] + map_tail(block, 1, diff)
+}
+
+/// Map the differences of a block of 32-bit pixels following `lastpix` into
+/// `diff`, returning their sum
+pub(crate) fn map_diffs_i32(block: &[i32], lastpix: i32, diff: &mut [u64]) -> u64 {
+    let diff = &mut diff[..block.len()];
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 was detected just above, and `diff` was cut to the
+            // length of `block`.
+            return unsafe { x86::map_diffs_i32_avx2(block, lastpix, diff) };
+        }
+        // SAFETY: SSE2 is part of the x86_64 baseline, and `diff` was cut to
+        // the length of `block`.
+        unsafe { x86::map_diffs_i32_sse2(block, lastpix, diff) }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        // SAFETY: NEON is part of the aarch64 baseline, and `diff` was cut to
+        // the length of `block`.
+        unsafe { neon::map_diffs_i32(block, lastpix, diff) }
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        map_diffs_portable(block, lastpix, diff)
+    }
+}
+
+/// As [`map_diffs_i32`] for 16-bit pixels
+pub(crate) fn map_diffs_i16(block: &[i16], lastpix: i16, diff: &mut [u64]) -> u64 {
+    let diff = &mut diff[..block.len()];
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 was detected just above, and `diff` was cut to the
+            // length of `block`.
+            return unsafe { x86::map_diffs_i16_avx2(block, lastpix, diff) };
+        }
+        // SAFETY: SSE2 is part of the x86_64 baseline, and `diff` was cut to
+        // the length of `block`.
+        unsafe { x86::map_diffs_i16_sse2(block, lastpix, diff) }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        // SAFETY: NEON is part of the aarch64 baseline, and `diff` was cut to
+        // the length of `block`.
+        unsafe { neon::map_diffs_i16(block, lastpix, diff) }
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        map_diffs_portable(block, lastpix, diff)
+    }
+}
+
+/*
+ * Every kernel maps the first pixel against lastpix as a scalar, then loads
+ * the current and previous pixels as two overlapping unaligned vectors. The
+ * difference d is mapped with (d << 1) ^ (d >> 31), which is the
+ * `!(d << 1)` / `d << 1` choice of map_diff without a branch. 16-bit
+ * differences are taken in 16-bit lanes and sign extended before mapping, as
+ * C promotes them to int.
+ */
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::map_tail;
+    use crate::sample::RiceSample;
+
+    /// Zigzag map 32-bit differences
+    ///
+    /// # Safety
+    /// The CPU must support SSE2, which every x86_64 CPU does.
+    #[inline]
+    unsafe fn map_sse2(d: __m128i) -> __m128i {
+        _mm_xor_si128(_mm_slli_epi32(d, 1), _mm_srai_epi32(d, 31))
+    }
+
+    /// Zero extend four mapped values to u64, store them at `out` and add
+    /// them to `acc`
+    ///
+    /// # Safety
+    /// The CPU must support SSE2, and `out` must be valid for writing four
+    /// `u64`s. No alignment is needed, as the stores are unaligned.
+    #[inline]
+    unsafe fn widen_store_sse2(m: __m128i, out: *mut u64, acc: __m128i) -> __m128i {
+        let zero = _mm_setzero_si128();
+        let lo = _mm_unpacklo_epi32(m, zero);
+        let hi = _mm_unpackhi_epi32(m, zero);
+        _mm_storeu_si128(out as *mut __m128i, lo);
+        _mm_storeu_si128(out.add(2) as *mut __m128i, hi);
+        _mm_add_epi64(acc, _mm_add_epi64(lo, hi))
+    }
+
+    /// Sum of the two lanes of `acc`
+    ///
+    /// # Safety
+    /// The CPU must support SSE2.
+    #[inline]
+    unsafe fn hsum_sse2(acc: __m128i) -> u64 {
+        let mut lanes = [0u64; 2];
+        _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, acc);
+        lanes[0] + lanes[1]
+    }
+
+    /// # Safety
+    /// `diff` must be at least as long as `block`. The vector loop reads
+    /// `block[i - 1..i + lanes]` and writes `diff[i..i + lanes]` only while
+    /// `i + lanes <= block.len()`, so every access is then in bounds.
+    pub(crate) unsafe fn map_diffs_i32_sse2(block: &[i32], lastpix: i32, diff: &mut [u64]) -> u64 {
+        if block.is_empty() {
+            return 0;
+        }
+        diff[0] = block[0].map_diff(lastpix);
+
+        let src = block.as_ptr();
+        let out = diff.as_mut_ptr();
+        let mut acc = _mm_setzero_si128();
+        let mut i = 1;
+        while i + 4 <= block.len() {
+            let cur = _mm_loadu_si128(src.add(i) as *const __m128i);
+            let prev = _mm_loadu_si128(src.add(i - 1) as *const __m128i);
+            let m = map_sse2(_mm_sub_epi32(cur, prev));
+            acc = widen_store_sse2(m, out.add(i), acc);
+            i += 4;
+        }
+
+        diff[0] + hsum_sse2(acc) + map_tail(block, i, diff)
+    }
+
+    /// # Safety
+    /// `diff` must be at least as long as `block`. The vector loop reads
+    /// `block[i - 1..i + lanes]` and writes `diff[i..i + lanes]` only while
+    /// `i + lanes <= block.len()`, so every access is then in bounds.
+    pub(crate) unsafe fn map_diffs_i16_sse2(block: &[i16], lastpix: i16, diff: &mut [u64]) -> u64 {
+        if block.is_empty() {
+            return 0;
+        }
+        diff[0] = block[0].map_diff(lastpix);
+
+        let src = block.as_ptr();
+        let out = diff.as_mut_ptr();
+        let mut acc = _mm_setzero_si128();
+        let mut i = 1;
+        while i + 8 <= block.len() {
+            let cur = _mm_loadu_si128(src.add(i) as *const __m128i);
+            let prev = _mm_loadu_si128(src.add(i - 1) as *const __m128i);
+            let d = _mm_sub_epi16(cur, prev);
+            /* sign extend by pairing each lane with itself and shifting down */
+            let lo = _mm_srai_epi32(_mm_unpacklo_epi16(d, d), 16);
+            let hi = _mm_srai_epi32(_mm_unpackhi_epi16(d, d), 16);
+            acc = widen_store_sse2(map_sse2(lo), out.add(i), acc);
+            acc = widen_store_sse2(map_sse2(hi), out.add(i + 4), acc);
+            i += 8;
+        }
+
+        diff[0] + hsum_sse2(acc) + map_tail(block, i, diff)
+    }
+
+    /// Zigzag map 32-bit differences
+    ///
+    /// # Safety
+    /// The CPU must support AVX2.
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn map_avx2(d: __m256i) -> __m256i {
+        _mm256_xor_si256(_mm256_slli_epi32(d, 1), _mm256_srai_epi32(d, 31))
+    }
+
+    /// Zero extend eight mapped values to u64, store them at `out` and add
+    /// them to `acc`
+    ///
+    /// # Safety
+    /// The CPU must support AVX2, and `out` must be valid for writing eight
+    /// `u64`s. No alignment is needed, as the stores are unaligned.
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn widen_store_avx2(m: __m256i, out: *mut u64, acc: __m256i) -> __m256i {
+        let lo = _mm256_cvtepu32_epi64(_mm256_castsi256_si128(m));
+        let hi = _mm256_cvtepu32_epi64(_mm256_extracti128_si256::<1>(m));
+        _mm256_storeu_si256(out as *mut __m256i, lo);
+        _mm256_storeu_si256(out.add(4) as *mut __m256i, hi);
+        _mm256_add_epi64(acc, _mm256_add_epi64(lo, hi))
+    }
+
+    /// Sum of the four lanes of `acc`
+    ///
+    /// # Safety
+    /// The CPU must support AVX2.
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn hsum_avx2(acc: __m256i) -> u64 {
+        let mut lanes = [0u64; 4];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, acc);
+        lanes.iter().sum()
+    }
+
+    /// # Safety
+    /// The CPU must support AVX2, and `diff` must be at least as long as
+    /// `block`. The vector loop reads `block[i - 1..i + lanes]` and writes
+    /// `diff[i..i + lanes]` only while `i + lanes <= block.len()`, so every
+    /// access is then in bounds.
+    #[target_feature(enable = "avx2")]
+    pub(crate) unsafe fn map_diffs_i32_avx2(block: &[i32], lastpix: i32, diff: &mut [u64]) -> u64 {
+        if block.is_empty() {
+            return 0;
+        }
+        diff[0] = block[0].map_diff(lastpix);
+
+        let src = block.as_ptr();
+        let out = diff.as_mut_ptr();
+        let mut acc = _mm256_setzero_si256();
+        let mut i = 1;
+        while i + 8 <= block.len() {
+            let cur = _mm256_loadu_si256(src.add(i) as *const __m256i);
+            let prev = _mm256_loadu_si256(src.add(i - 1) as *const __m256i);
+            let m = map_avx2(_mm256_sub_epi32(cur, prev));
+            acc = widen_store_avx2(m, out.add(i), acc);
+            i += 8;
+        }
+
+        diff[0] + hsum_avx2(acc) + map_tail(block, i, diff)
+    }
+
+    /// # Safety
+    /// The CPU must support AVX2, and `diff` must be at least as long as
+    /// `block`. The vector loop reads `block[i - 1..i + lanes]` and writes
+    /// `diff[i..i + lanes]` only while `i + lanes <= block.len()`, so every
+    /// access is then in bounds.
+    #[target_feature(enable = "avx2")]
+    pub(crate) unsafe fn map_diffs_i16_avx2(block: &[i16], lastpix: i16, diff: &mut [u64]) -> u64 {
+        if block.is_empty() {
+            return 0;
+        }
+        diff[0] = block[0].map_diff(lastpix);
+
+        let src = block.as_ptr();
+        let out = diff.as_mut_ptr();
+        let mut acc = _mm256_setzero_si256();
+        let mut i = 1;
+        while i + 16 <= block.len() {
+            let cur = _mm256_loadu_si256(src.add(i) as *const __m256i);
+            let prev = _mm256_loadu_si256(src.add(i - 1) as *const __m256i);
+            let d = _mm256_sub_epi16(cur, prev);
+            let lo = _mm256_cvtepi16_epi32(_mm256_castsi256_si128(d));
+            let hi = _mm256_cvtepi16_epi32(_mm256_extracti128_si256::<1>(d));
+            acc = widen_store_avx2(map_avx2(lo), out.add(i), acc);
+            acc = widen_store_avx2(map_avx2(hi), out.add(i + 8), acc);
+            i += 16;
+        }
+
+        diff[0] + hsum_avx2(acc) + map_tail(block, i, diff)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+pub(crate) mod neon {
+    use std::arch::aarch64::*;
+
+    use super::map_tail;
+    use crate::sample::RiceSample;
+
+    /// Zigzag map 32-bit differences
+    ///
+    /// # Safety
+    /// The CPU must support NEON, which every aarch64 CPU does.
+    #[inline]
+    unsafe fn map(d: int32x4_t) -> uint32x4_t {
+        vreinterpretq_u32_s32(veorq_s32(vshlq_n_s32::<1>(d), vshrq_n_s32::<31>(d)))
+    }
+
+    /// Zero extend four mapped values to u64, store them at `out` and add
+    /// them to `acc`
+    ///
+    /// # Safety
+    /// The CPU must support NEON, and `out` must be valid for writing four
+    /// `u64`s, aligned for `u64`.
+    #[inline]
+    unsafe fn widen_store(m: uint32x4_t, out: *mut u64, acc: uint64x2_t) -> uint64x2_t {
+        let lo = vmovl_u32(vget_low_u32(m));
+        let hi = vmovl_high_u32(m);
+        vst1q_u64(out, lo);
+        vst1q_u64(out.add(2), hi);
+        vaddq_u64(acc, vaddq_u64(lo, hi))
+    }
+
+    /// # Safety
+    /// `diff` must be at least as long as `block`. The vector loop reads
+    /// `block[i - 1..i + lanes]` and writes `diff[i..i + lanes]` only while
+    /// `i + lanes <= block.len()`, so every access is then in bounds.
+    pub(crate) unsafe fn map_diffs_i32(block: &[i32], lastpix: i32, diff: &mut [u64]) -> u64 {
+        if block.is_empty() {
+            return 0;
+        }
+        diff[0] = block[0].map_diff(lastpix);
+
+        let src = block.as_ptr();
+        let out = diff.as_mut_ptr();
+        let mut acc = vdupq_n_u64(0);
+        let mut i = 1;
+        while i + 4 <= block.len() {
+            let cur = vld1q_s32(src.add(i));
+            let prev = vld1q_s32(src.add(i - 1));
+            acc = widen_store(map(vsubq_s32(cur, prev)), out.add(i), acc);
+            i += 4;
+        }
+
+        diff[0] + vaddvq_u64(acc) + map_tail(block, i, diff)
+    }
+
+    /// # Safety
+    /// `diff` must be at least as long as `block`. The vector loop reads
+    /// `block[i - 1..i + lanes]` and writes `diff[i..i + lanes]` only while
+    /// `i + lanes <= block.len()`, so every access is then in bounds.
+    pub(crate) unsafe fn map_diffs_i16(block: &[i16], lastpix: i16, diff: &mut [u64]) -> u64 {
+        if block.is_empty() {
+            return 0;
+        }
+        diff[0] = block[0].map_diff(lastpix);
+
+        let src = block.as_ptr();
+        let out = diff.as_mut_ptr();
+        let mut acc = vdupq_n_u64(0);
+        let mut i = 1;
+        while i + 8 <= block.len() {
+            let cur = vld1q_s16(src.add(i));
+            let prev = vld1q_s16(src.add(i - 1));
+            let d = vsubq_s16(cur, prev);
+            let lo = vmovl_s16(vget_low_s16(d));
+            let hi = vmovl_high_s16(d);
+            acc = widen_store(map(lo), out.add(i), acc);
+            acc = widen_store(map(hi), out.add(i + 4), acc);
+            i += 8;
+        }
+
+        diff[0] + vaddvq_u64(acc) + map_tail(block, i, diff)
+    }
+}
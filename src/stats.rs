@@ -0,0 +1,37 @@
+//! Per-block report from [`RCEncoder::encode_with_stats`](crate::write::RCEncoder::encode_with_stats)
+//! and [`RCDecoder::decode_with_stats`](crate::read::RCDecoder::decode_with_stats).
+//!
+//! Both sides report the same values for the same stream, so a report can be
+//! rebuilt from compressed data alone.
+
+/// How a block was coded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlockKind {
+    /// All differences were zero, only the block header was written
+    Zero,
+    /// Rice coded with split parameter `fs`
+    Rice,
+    /// High-entropy escape, differences written directly
+    Raw,
+}
+
+/// Statistics for one coding block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockStats {
+    /// Number of pixels in the block; only the last block can be short
+    pub pixels: usize,
+    pub kind: BlockKind,
+    /// Split parameter: 0 for a zero block and FSMAX for a raw block
+    pub fs: u32,
+    /// Coded length of the block in bits, including its header
+    pub bits: u64,
+    /// Sum of the mapped differences of the block
+    pub pixelsum: u128,
+}
+
+impl BlockStats {
+    /// Average coded bits per pixel
+    pub fn bits_per_pixel(&self) -> f64 {
+        self.bits as f64 / self.pixels as f64
+    }
+}
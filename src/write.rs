@@ -1,23 +1,96 @@
-use std::{ffi::c_int, io::Write};
-
-use crate::{log_noop, EOF};
+use std::{
+    ffi::c_int,
+    fmt,
+    io::{self, ErrorKind, Write},
+};
+
+use crate::{
+    frame::{self, ChecksumWriter, FrameHeader},
+    log_noop,
+    nulls::NullMask,
+    sample::{self, RiceSample, SampleWidth},
+    stats::{BlockKind, BlockStats},
+};
 
 #[derive(Debug)]
 pub enum EncodeError {
-    EndOfBuffer,
+    /// The output sink ran out of room before the whole stream was written;
+    /// `needed` is the size in bytes of the complete stream. When streaming
+    /// the total is not known yet, so it is only a lower bound: the number of
+    /// bytes produced so far.
+    EndOfBuffer {
+        needed: usize,
+    },
     ZeroSizeInput,
+    /// The output sink returned an I/O error.
+    Io(io::Error),
+    /// An earlier call to [`RCStreamEncoder::push`] failed part way through a
+    /// block, so the stream cannot be continued.
+    Poisoned,
+    /// The block size is larger than a framed stream allows,
+    /// [`frame::MAX_BLOCK`]
+    BlockTooLarge,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::EndOfBuffer { needed } => {
+                write!(f, "end of output buffer, {} bytes needed", needed)
+            }
+            EncodeError::ZeroSizeInput => write!(f, "zero size input"),
+            EncodeError::Io(err) => write!(f, "error writing output: {}", err),
+            EncodeError::Poisoned => write!(f, "stream encoder used after an error"),
+            EncodeError::BlockTooLarge => write!(f, "block size too large for a framed stream"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EncodeError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EncodeError {
+    fn from(err: io::Error) -> Self {
+        EncodeError::Io(err)
+    }
 }
 
 #[derive(Debug, Default)]
 struct Buffer {
-    bitbuffer: c_int,  /* bit buffer			*/
+    bitbuffer: i64,    /* bit buffer			*/
     bits_to_go: c_int, /* bits to go in buffer	*/
     current: usize,    /* current position in buffer	*/
 }
 
+/// How the encoder picks the split parameter `fs` of each block
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FsStrategy {
+    /// Estimate `fs` from the mean mapped difference, as CFITSIO does. The
+    /// output is byte-identical to `fits_rcomp`.
+    #[default]
+    Estimate,
+    /// Work out the coded length of the block for every `fs`, and for the
+    /// all-zero and directly coded cases, and pick the shortest. Slower, and
+    /// the bytes differ from CFITSIO's, but any decoder can read them.
+    Optimal,
+}
+
+/// Output is staged in chunks of this many bytes before being handed to the
+/// sink, so a `File` or socket sees a few large writes rather than one per byte
+const OUTPUT_CHUNK: usize = 8192;
+
 pub struct RCEncoder<W: Write> {
     log_fn: fn(&str),
+    fs_strategy: FsStrategy,
+    stats: Option<Vec<BlockStats>>, /* collected by encode_with_stats */
     buffer: Buffer,
+    out: Vec<u8>, /* bytes not yet written to inner */
     inner: W,
 }
 
@@ -25,7 +98,10 @@ impl<W: Write> RCEncoder<W> {
     pub fn new(inner: W) -> Self {
         RCEncoder {
             log_fn: log_noop,
+            fs_strategy: FsStrategy::default(),
+            stats: None,
             buffer: Buffer::default(),
+            out: Vec::with_capacity(OUTPUT_CHUNK),
             inner,
         }
     }
@@ -34,238 +110,292 @@ impl<W: Write> RCEncoder<W> {
         self.log_fn = log_fn;
     }
 
-    pub fn encode(
+    pub fn set_fs_strategy(&mut self, fs_strategy: FsStrategy) {
+        self.fs_strategy = fs_strategy;
+    }
+
+    /// Compress `nx` pixels of `input` in blocks of `nblock` pixels, returning
+    /// the number of bytes written.
+    ///
+    /// This is `fits_rcomp`, `fits_rcomp_short` or `fits_rcomp_byte` depending
+    /// on the width of `T`. If the sink runs out of room the error reports the
+    /// size of the complete stream.
+    pub fn encode<T: RiceSample>(
         &mut self,
-        input: &[i32], /* input array			*/
+        input: &[T],   /* input array			*/
         nx: usize,     /* number of input pixels	*/
         nblock: usize, /* coding block size		*/
     ) -> Result<usize, EncodeError> {
-        if input.is_empty() || nblock == 0 {
-            return Err(EncodeError::ZeroSizeInput);
+        match self.encode_stream(input, nx, nblock) {
+            Err(EncodeError::EndOfBuffer { .. }) => {
+                /* count the bits of the whole stream to report its size */
+                let needed = self.compressed_len(&input[..nx], nblock)?;
+                Err(EncodeError::EndOfBuffer { needed })
+            }
+            result => result,
         }
+    }
 
-        let mut nextpix: i32;
-        let mut pdiff: i32;
+    /// As [`encode`](Self::encode), also returning how each block was coded
+    pub fn encode_with_stats<T: RiceSample>(
+        &mut self,
+        input: &[T],
+        nx: usize,
+        nblock: usize,
+    ) -> Result<(usize, Vec<BlockStats>), EncodeError> {
+        self.stats = Some(Vec::with_capacity(nx.div_ceil(nblock.max(1))));
+        let result = self.encode(input, nx, nblock);
+        let stats = self.stats.take().unwrap_or_default();
+        result.map(|len| (len, stats))
+    }
 
-        let mut v: i32;
-        let mut fs: i32;
-        let mut fsmask: i32;
-        let mut top: i32;
+    /// Block size from `candidates` giving the shortest stream for `input`,
+    /// which may be a representative sample of the data. Ties go to the
+    /// earliest candidate; zero candidates are skipped.
+    pub fn choose_block_size<T: RiceSample>(
+        &self,
+        input: &[T],
+        candidates: &[usize],
+    ) -> Result<usize, EncodeError> {
+        let mut best: Option<(usize, usize)> = None;
+        for &nblock in candidates.iter().filter(|&&nblock| nblock > 0) {
+            let len = self.compressed_len(input, nblock)?;
+            if best.is_none_or(|(_, best_len)| len < best_len) {
+                best = Some((nblock, len));
+            }
+        }
+        best.map(|(nblock, _)| nblock)
+            .ok_or(EncodeError::ZeroSizeInput)
+    }
 
-        let mut lbitbuffer: i32;
-        let mut lbits_to_go: i32;
+    /// Compress all of `input` with the block size from
+    /// [`choose_block_size`](Self::choose_block_size), returning the number of
+    /// bytes written and the block size. The decoder needs the block size, so
+    /// store it with the data, e.g. in the FITS `ZVAL1` keyword.
+    pub fn encode_auto<T: RiceSample>(
+        &mut self,
+        input: &[T],
+        candidates: &[usize],
+    ) -> Result<(usize, usize), EncodeError> {
+        let nblock = self.choose_block_size(input, candidates)?;
+        let len = self.encode(input, input.len(), nblock)?;
+        Ok((len, nblock))
+    }
 
-        let mut psum: u32;
-        let mut pixelsum: f64;
-        let mut dpsum: f64;
+    /// Compress `nx` pixels of `input` coded at `width` rather than the width
+    /// of `T`. Values are narrowed by keeping their low bits, so `width` must
+    /// hold them all, e.g. as chosen by [`SampleWidth::narrowest`].
+    /// [`RCDecoder::decode_as`](crate::read::RCDecoder::decode_as) with the
+    /// same width reads them back.
+    pub fn encode_as<T: RiceSample>(
+        &mut self,
+        input: &[T],
+        nx: usize,
+        nblock: usize,
+        width: SampleWidth,
+    ) -> Result<usize, EncodeError> {
+        match width {
+            _ if width == T::WIDTH => self.encode(input, nx, nblock),
+            SampleWidth::Byte => self.encode(&sample::convert::<T, u8>(input), nx, nblock),
+            SampleWidth::Short => self.encode(&sample::convert::<T, i16>(input), nx, nblock),
+            SampleWidth::Int => self.encode(&sample::convert::<T, i32>(input), nx, nblock),
+            SampleWidth::Long => self.encode(&sample::convert::<T, i64>(input), nx, nblock),
+        }
+    }
 
-        /*
-         * From bsize derive:
-         * FSBITS = # bits required to store FS
-         * FSMAX = maximum value for FS
-         * BBITS = bits/pixel for direct coding
-         */
+    /// Compress all of `input` at the narrowest width that holds its values,
+    /// returning the number of bytes written and the width used. The decoder
+    /// needs the width, so store it with the data, e.g. in the FITS `ZVAL2`
+    /// keyword.
+    pub fn encode_narrowest<T: RiceSample + PartialEq>(
+        &mut self,
+        input: &[T],
+        nblock: usize,
+    ) -> Result<(usize, SampleWidth), EncodeError> {
+        let width = SampleWidth::narrowest(input);
+        let len = self.encode_as(input, input.len(), nblock, width)?;
+        Ok((len, width))
+    }
 
-        /* move out of switch block, to tweak performance */
-        let fsbits: i32 = 5;
-        let fsmax: i32 = 25;
+    /// Compress all of `input`, keeping pixels equal to `null` out of the
+    /// differences, and return the number of bytes written with the mask of
+    /// null pixels. The decoder needs the mask to restore them, see
+    /// [`RCDecoder::decode_nulls`](crate::read::RCDecoder::decode_nulls).
+    pub fn encode_nulls<T: RiceSample + PartialEq>(
+        &mut self,
+        input: &[T],
+        nblock: usize,
+        null: T,
+    ) -> Result<(usize, NullMask), EncodeError> {
+        let mask = NullMask::from_pixels(input, null);
+        let len = self.encode(&mask.fill(input), input.len(), nblock)?;
+        Ok((len, mask))
+    }
 
-        let bbits: i32 = 1 << fsbits;
+    /// Compress all of `input` as a framed stream: a header recording the
+    /// sample width, block size and pixel count, the coded pixels, and a
+    /// CRC-32 of the coded pixels if `checksum` is set. Returns the number of
+    /// bytes written, including the header.
+    ///
+    /// See the [`frame`](crate::frame) module for the layout. The result can
+    /// only be read by [`RCDecoder::decode_framed`](crate::read::RCDecoder::decode_framed).
+    pub fn encode_framed<T: RiceSample>(
+        &mut self,
+        input: &[T],
+        nblock: usize,
+        checksum: bool,
+    ) -> Result<usize, EncodeError> {
+        match self.encode_framed_stream(input, nblock, checksum) {
+            Err(EncodeError::EndOfBuffer { .. }) => {
+                /* count the bits of the whole stream to report its size */
+                let trailer = if checksum { frame::CHECKSUM_LEN } else { 0 };
+                let needed = frame::HEADER_LEN + self.compressed_len(input, nblock)? + trailer;
+                Err(EncodeError::EndOfBuffer { needed })
+            }
+            result => result,
+        }
+    }
 
-        /*
-         * Set up buffer pointers
-         */
-        self.buffer = Buffer {
-            current: 0,
-            bits_to_go: 8,
-            bitbuffer: 0,
+    fn encode_framed_stream<T: RiceSample>(
+        &mut self,
+        input: &[T],
+        nblock: usize,
+        checksum: bool,
+    ) -> Result<usize, EncodeError> {
+        if input.is_empty() || nblock == 0 {
+            return Err(EncodeError::ZeroSizeInput);
+        }
+        if nblock > frame::MAX_BLOCK {
+            return Err(EncodeError::BlockTooLarge);
+        }
+
+        let header = FrameHeader {
+            width: T::WIDTH,
+            nblock,
+            nx: input.len(),
+            checksum,
         };
+        self.write_raw(&header.to_bytes())?;
+
+        let mut encoder = RCEncoder::new(ChecksumWriter::new(&mut self.inner));
+        encoder.set_log_fn(self.log_fn);
+        encoder.set_fs_strategy(self.fs_strategy);
+        let len = encoder.encode_stream(input, input.len(), nblock)?;
+        let crc = encoder.inner.crc.finish();
+
+        if checksum {
+            self.write_raw(&crc.to_be_bytes())?;
+            return Ok(frame::HEADER_LEN + len + frame::CHECKSUM_LEN);
+        }
+        Ok(frame::HEADER_LEN + len)
+    }
 
-        // output.reserve(nx * 4);
+    fn encode_stream<T: RiceSample>(
+        &mut self,
+        input: &[T],   /* input array			*/
+        nx: usize,     /* number of input pixels	*/
+        nblock: usize, /* coding block size		*/
+    ) -> Result<usize, EncodeError> {
+        if input.is_empty() || nblock == 0 {
+            return Err(EncodeError::ZeroSizeInput);
+        }
 
         /*
          * array for differences mapped to non-negative values
          */
-        let mut diff: Vec<u32> = vec![0; nblock];
+        let mut diff: Vec<u64> = vec![0; nblock];
 
         /*
          * Code in blocks of nblock pixels
          */
+        self.start_outputing_bits();
 
-        // Initialize for bit output
-        self.buffer.bitbuffer = 0;
-        self.buffer.bits_to_go = 8;
-
-        /* write out first int value to the first 4 bytes of the buffer */
-        if self.output_nbits(input[0], 32) == EOF {
-            (self.log_fn)("rice_encode: end of buffer");
-            return Err(EncodeError::EndOfBuffer);
-        }
-
-        let mut lastpix: i32 = input[0]; /* the first difference will always be zero */
-
-        let mut thisblock: usize = nblock;
+        /* write out first value to the first bbits/8 bytes of the buffer */
+        self.output_nbits(input[0].to_bits() as i64, T::BBITS)?;
 
-        for i in (0..nx).step_by(nblock) {
-            // for (i=0; i<nx; i += nblock) {
-            /* last block may be shorter */
-            if nx - i < nblock {
-                thisblock = nx - i;
-            }
-            /*
-             * Compute differences of adjacent pixels and map them to unsigned values.
-             * Note that this may overflow the integer variables -- that's
-             * OK, because we can recover when decompressing.  If we were
-             * compressing shorts or bytes, would want to do this arithmetic
-             * with short/byte working variables (though diff will still be
-             * passed as an int.)
-             *
-             * compute sum of mapped pixel values at same time
-             * use double precision for sum to allow 32-bit integer inputs
-             */
-            pixelsum = 0.0;
-            for j in 0..thisblock {
-                nextpix = input[i + j];
-                pdiff = nextpix.wrapping_sub(lastpix);
-                diff[j] = (if pdiff < 0 { !(pdiff << 1) } else { pdiff << 1 }) as u32; // ! is bitwise complement
-                pixelsum += diff[j] as f64;
-                lastpix = nextpix;
-            }
-
-            /*
-             * compute number of bits to split from sum
-             */
-            dpsum = (pixelsum - ((thisblock as f64) / 2.0) - 1.0) / (thisblock as f64);
-            if dpsum < 0.0 {
-                dpsum = 0.0;
-            }
-            psum = (dpsum as u32) >> 1;
+        let mut lastpix: T = input[0]; /* the first difference will always be zero */
 
-            fs = 0;
-            while psum > 0 {
-                psum >>= 1;
-                fs += 1;
-            }
-
-            /*
-             * write the codes
-             * fsbits ID bits used to indicate split level
-             */
-            if fs >= fsmax {
-                /* Special high entropy case when FS >= fsmax
-                 * Just write pixel difference values directly, no Rice coding at all.
-                 */
-                if self.output_nbits(fsmax + 1, fsbits) == EOF {
-                    (self.log_fn)("rice_encode: end of buffer");
-                    return Err(EncodeError::EndOfBuffer);
-                }
-
-                for &diff_item in diff.iter().take(thisblock) {
-                    if self.output_nbits(diff_item as i32, bbits) == EOF {
-                        (self.log_fn)("rice_encode: end of buffer");
-                        return Err(EncodeError::EndOfBuffer);
-                    }
-                }
-            } else if fs == 0 && pixelsum == 0.0 {
-                /*
-                 * special low entropy case when FS = 0 and pixelsum=0 (all
-                 * pixels in block are zero.)
-                 * Output a 0 and return
-                 */
-                if self.output_nbits(0, fsbits) == EOF {
-                    (self.log_fn)("rice_encode: end of buffer");
-                    return Err(EncodeError::EndOfBuffer);
-                }
-            } else {
-                /* normal case: not either very high or very low entropy */
-                if self.output_nbits(fs + 1, fsbits) == EOF {
-                    (self.log_fn)("rice_encode: end of buffer");
-                    return Err(EncodeError::EndOfBuffer);
-                }
-                fsmask = (1 << fs) - 1;
-                /*
-                 * local copies of bit buffer to improve optimization
-                 */
-                lbitbuffer = self.buffer.bitbuffer;
-                lbits_to_go = self.buffer.bits_to_go;
-                for &diff_item in diff.iter().take(thisblock) {
-                    v = diff_item as i32;
-                    top = v >> fs;
-                    /*
-                     * top is coded by top zeros + 1
-                     */
-                    if lbits_to_go > top {
-                        lbitbuffer = lbitbuffer.wrapping_shl((top + 1) as u32);
-                        lbitbuffer |= 1;
-                        lbits_to_go -= top + 1;
-                    } else {
-                        lbitbuffer <<= lbits_to_go;
-                        self.putcbuf(lbitbuffer & 0xff);
-
-                        top -= lbits_to_go;
-                        while top >= 8 {
-                            self.putcbuf(0);
-                            top -= 8;
-                        }
-
-                        lbitbuffer = 1;
-                        lbits_to_go = 7 - top;
-                    }
-                    /*
-                     * bottom FS bits are written without coding
-                     * code is output_nbits, moved into this routine to reduce overheads
-                     * This code potentially breaks if FS>24, so I am limiting
-                     * FS to 24 by choice of FSMAX above.
-                     */
-                    if fs > 0 {
-                        lbitbuffer <<= fs;
-                        lbitbuffer |= v & fsmask;
-                        lbits_to_go -= fs;
-                        while lbits_to_go <= 0 {
-                            self.putcbuf((lbitbuffer >> (-lbits_to_go)) & 0xff);
-                            lbits_to_go += 8;
-                        }
-                    }
-                }
-
-                self.buffer.bitbuffer = lbitbuffer;
-                self.buffer.bits_to_go = lbits_to_go;
-            }
+        /* last block may be shorter */
+        for block in input[..nx].chunks(nblock) {
+            lastpix = self.encode_block(block, lastpix, &mut diff)?;
         }
 
-        // Flush out the last bits
-        if self.buffer.bits_to_go < 8 {
-            self.putcbuf(self.buffer.bitbuffer << self.buffer.bits_to_go);
-        }
+        self.done_outputing_bits()?;
 
         // return number of bytes used
         Ok(self.buffer.current)
     }
 
-    pub fn encode_short(
-        &mut self,
-        input: &[i16], /* input array			*/
-        nx: usize,     /* number of input pixels	*/
-        nblock: usize, /* coding block size		*/
+    /// Map the differences of a block following `lastpix` into `diff` and pick
+    /// its split parameter, returning `fs` (`fsmax` or more for a directly
+    /// coded block) and the sum of the mapped differences.
+    fn choose_fs<T: RiceSample>(&self, block: &[T], lastpix: T, diff: &mut [u64]) -> (i32, u128) {
+        /*
+         * Compute differences of adjacent pixels and map them to unsigned values.
+         * Note that this may overflow the integer variables -- that's
+         * OK, because we can recover when decompressing.  If we were
+         * compressing shorts or bytes, would want to do this arithmetic
+         * with short/byte working variables (though diff will still be
+         * passed as an int.)
+         *
+         * compute sum of mapped pixel values at same time, exactly in
+         * integers (see the simd module for the vectorised version)
+         */
+        let pixelsum = T::map_block(block, lastpix, diff);
+
+        /*
+         * compute number of bits to split from sum
+         */
+        let fs = match self.fs_strategy {
+            FsStrategy::Estimate => split_fs(pixelsum, block.len()),
+            FsStrategy::Optimal => optimal_fs(diff, T::FSMAX, T::BBITS),
+        };
+        (fs, pixelsum)
+    }
+
+    /// Exact size in bytes of the stream [`encode`](Self::encode) would write
+    /// for all of `input`, worked out by adding up the coded length of each
+    /// block without producing any output. Uses the encoder's
+    /// [`FsStrategy`].
+    pub fn compressed_len<T: RiceSample>(
+        &self,
+        input: &[T],
+        nblock: usize,
     ) -> Result<usize, EncodeError> {
         if input.is_empty() || nblock == 0 {
             return Err(EncodeError::ZeroSizeInput);
         }
 
-        let mut nextpix: i16;
-        let mut pdiff: i16;
+        let mut diff: Vec<u64> = vec![0; nblock];
 
-        let mut v: i32;
-        let mut fs: i32;
-        let mut fsmask: i32;
-        let mut top: i32;
+        /* the first value is written raw */
+        let mut bits = T::BBITS as u128;
+        let mut lastpix = input[0];
+        for block in input.chunks(nblock) {
+            let diff = &mut diff[..block.len()];
+            let (fs, pixelsum) = self.choose_fs(block, lastpix, diff);
+            lastpix = block[block.len() - 1];
 
-        let mut lbitbuffer: i32;
-        let mut lbits_to_go: i32;
+            bits += block_bits::<T>(diff, fs, pixelsum).1;
+        }
 
-        let mut psum: u16;
-        let mut pixelsum: f64;
-        let mut dpsum: f64;
+        Ok(bits.div_ceil(8) as usize)
+    }
+
+    /// Code one block of pixels following `lastpix`, returning the last pixel
+    /// of the block. `diff` is scratch space of at least `block.len()`.
+    fn encode_block<T: RiceSample>(
+        &mut self,
+        block: &[T],
+        mut lastpix: T,
+        diff: &mut [u64],
+    ) -> Result<T, EncodeError> {
+        let mut v: u64;
+        let fsmask: u64;
+        let mut top: u64;
+
+        let mut lbitbuffer: i64;
+        let mut lbits_to_go: i32;
 
         /*
          * From bsize derive:
@@ -274,218 +404,111 @@ impl<W: Write> RCEncoder<W> {
          * BBITS = bits/pixel for direct coding
          */
 
-        /* move out of switch block, to tweak performance */
-        let fsbits: i32 = 4;
-        let fsmax: i32 = 14;
-
-        let bbits: i32 = 1 << fsbits;
-
-        /*
-         * Set up buffer pointers
-         */
-        self.buffer = Buffer {
-            current: 0,
-            bits_to_go: 8,
-            bitbuffer: 0,
-        };
+        let fsbits: i32 = T::FSBITS;
+        let fsmax: i32 = T::FSMAX;
 
-        /*
-         * array for differences mapped to non-negative values
-         */
-        let mut diff: Vec<u32> = vec![0; nblock];
+        let bbits: i32 = T::BBITS;
 
-        /*
-         * Code in blocks of nblock pixels
-         */
+        let thisblock = block.len();
+        let diff = &mut diff[..thisblock];
 
-        // Initialize for bit output
-        self.buffer.bitbuffer = 0;
-        self.buffer.bits_to_go = 8;
+        let (fs, pixelsum) = self.choose_fs(block, lastpix, diff);
+        lastpix = block[thisblock - 1];
 
-        /* write out first int value to the first 4 bytes of the buffer */
-        if self.output_nbits(input[0].into(), 16) == EOF {
-            (self.log_fn)("rice_encode: end of buffer");
-            return Err(EncodeError::EndOfBuffer);
+        if let Some(stats) = &mut self.stats {
+            let (kind, bits) = block_bits::<T>(diff, fs, pixelsum);
+            stats.push(BlockStats {
+                pixels: thisblock,
+                kind,
+                fs: fs.min(fsmax) as u32,
+                bits: bits as u64,
+                pixelsum,
+            });
         }
 
-        let mut lastpix: i16 = input[0]; /* the first difference will always be zero */
-
-        let mut thisblock: usize = nblock;
-
-        for i in (0..nx).step_by(nblock) {
-            // for (i=0; i<nx; i += nblock) {
-            /* last block may be shorter */
-            if nx - i < nblock {
-                thisblock = nx - i;
-            }
-            /*
-             * Compute differences of adjacent pixels and map them to unsigned values.
-             * Note that this may overflow the integer variables -- that's
-             * OK, because we can recover when decompressing.  If we were
-             * compressing shorts or bytes, would want to do this arithmetic
-             * with short/byte working variables (though diff will still be
-             * passed as an int.)
-             *
-             * compute sum of mapped pixel values at same time
-             * use double precision for sum to allow 32-bit integer inputs
+        /*
+         * write the codes
+         * fsbits ID bits used to indicate split level
+         */
+        if fs >= fsmax {
+            /* Special high entropy case when FS >= fsmax
+             * Just write pixel difference values directly, no Rice coding at all.
              */
-            pixelsum = 0.0;
-            for j in 0..thisblock {
-                nextpix = input[i + j];
-                pdiff = nextpix.wrapping_sub(lastpix);
-                diff[j] = (if pdiff < 0 { !(pdiff << 1) } else { pdiff << 1 }) as u32; // ! is bitwise complement
-                pixelsum += diff[j] as f64;
-                lastpix = nextpix;
-            }
+            self.output_nbits((fsmax + 1).into(), fsbits)?;
 
+            for &diff_item in diff.iter() {
+                self.output_nbits(diff_item as i64, bbits)?;
+            }
+        } else if fs == 0 && pixelsum == 0 {
             /*
-             * compute number of bits to split from sum
+             * special low entropy case when FS = 0 and pixelsum=0 (all
+             * pixels in block are zero.)
+             * Output a 0 and return
              */
-            dpsum = (pixelsum - ((thisblock as f64) / 2.0) - 1.0) / (thisblock as f64);
-            if dpsum < 0.0 {
-                dpsum = 0.0;
-            }
-            psum = (dpsum as u16) >> 1;
-
-            fs = 0;
-            while psum > 0 {
-                psum >>= 1;
-                fs += 1;
-            }
-
+            self.output_nbits(0, fsbits)?;
+        } else {
+            /* normal case: not either very high or very low entropy */
+            self.output_nbits((fs + 1).into(), fsbits)?;
+            fsmask = (1 << fs) - 1;
             /*
-             * write the codes
-             * fsbits ID bits used to indicate split level
+             * local copies of bit buffer to improve optimization
              */
-            if fs >= fsmax {
-                /* Special high entropy case when FS >= fsmax
-                 * Just write pixel difference values directly, no Rice coding at all.
+            lbitbuffer = self.buffer.bitbuffer;
+            lbits_to_go = self.buffer.bits_to_go;
+            for &diff_item in diff.iter() {
+                /*
+                 * The mapped difference must stay unsigned here: the
+                 * largest differences (>= 2^31 for 32-bit samples) would
+                 * otherwise turn negative and produce a negative top.
                  */
-                if self.output_nbits(fsmax + 1, fsbits) == EOF {
-                    (self.log_fn)("rice_encode: end of buffer");
-                    return Err(EncodeError::EndOfBuffer);
-                }
-
-                for &diff_item in diff.iter().take(thisblock) {
-                    if self.output_nbits(diff_item as i32, bbits) == EOF {
-                        (self.log_fn)("rice_encode: end of buffer");
-                        return Err(EncodeError::EndOfBuffer);
-                    }
-                }
-            } else if fs == 0 && pixelsum == 0.0 {
+                v = diff_item;
+                top = v >> fs;
                 /*
-                 * special low entropy case when FS = 0 and pixelsum=0 (all
-                 * pixels in block are zero.)
-                 * Output a 0 and return
+                 * top is coded by top zeros + 1
                  */
-                if self.output_nbits(0, fsbits) == EOF {
-                    (self.log_fn)("rice_encode: end of buffer");
-                    return Err(EncodeError::EndOfBuffer);
-                }
-            } else {
-                /* normal case: not either very high or very low entropy */
-                if self.output_nbits(fs + 1, fsbits) == EOF {
-                    (self.log_fn)("rice_encode: end of buffer");
-                    return Err(EncodeError::EndOfBuffer);
+                if lbits_to_go as u64 > top {
+                    lbitbuffer = lbitbuffer.wrapping_shl((top + 1) as u32);
+                    lbitbuffer |= 1;
+                    lbits_to_go -= (top + 1) as i32;
+                } else {
+                    lbitbuffer <<= lbits_to_go;
+                    self.putcbuf(lbitbuffer & 0xff)?;
+
+                    top -= lbits_to_go as u64;
+                    while top >= 8 {
+                        self.putcbuf(0)?;
+                        top -= 8;
+                    }
+
+                    lbitbuffer = 1;
+                    lbits_to_go = 7 - top as i32;
                 }
-                fsmask = (1 << fs) - 1;
                 /*
-                 * local copies of bit buffer to improve optimization
+                 * bottom FS bits are written without coding
+                 * code is output_nbits, moved into this routine to reduce overheads
+                 * This code potentially breaks if FS + 8 exceeds the width of
+                 * the bit buffer, so FSMAX is limited accordingly for each
+                 * sample width.
                  */
-                lbitbuffer = self.buffer.bitbuffer;
-                lbits_to_go = self.buffer.bits_to_go;
-                for &diff_item in diff.iter().take(thisblock) {
-                    v = diff_item as i32;
-                    top = v >> fs;
-                    /*
-                     * top is coded by top zeros + 1
-                     */
-                    if lbits_to_go > top {
-                        lbitbuffer = lbitbuffer.wrapping_shl((top + 1) as u32);
-                        lbitbuffer |= 1;
-                        lbits_to_go -= top + 1;
-                    } else {
-                        lbitbuffer <<= lbits_to_go;
-                        self.putcbuf(lbitbuffer & 0xff);
-
-                        top -= lbits_to_go;
-                        while top >= 8 {
-                            self.putcbuf(0);
-                            top -= 8;
-                        }
-
-                        lbitbuffer = 1;
-                        lbits_to_go = 7 - top;
-                    }
-                    /*
-                     * bottom FS bits are written without coding
-                     * code is output_nbits, moved into this routine to reduce overheads
-                     * This code potentially breaks if FS>24, so I am limiting
-                     * FS to 24 by choice of FSMAX above.
-                     */
-                    if fs > 0 {
-                        lbitbuffer <<= fs;
-                        lbitbuffer |= v & fsmask;
-                        lbits_to_go -= fs;
-                        while lbits_to_go <= 0 {
-                            self.putcbuf((lbitbuffer >> (-lbits_to_go)) & 0xff);
-                            lbits_to_go += 8;
-                        }
+                if fs > 0 {
+                    lbitbuffer <<= fs;
+                    lbitbuffer |= (v & fsmask) as i64;
+                    lbits_to_go -= fs;
+                    while lbits_to_go <= 0 {
+                        self.putcbuf((lbitbuffer >> (-lbits_to_go)) & 0xff)?;
+                        lbits_to_go += 8;
                     }
                 }
-
-                self.buffer.bitbuffer = lbitbuffer;
-                self.buffer.bits_to_go = lbits_to_go;
             }
-        }
 
-        // Flush out the last bits
-        if self.buffer.bits_to_go < 8 {
-            self.putcbuf(self.buffer.bitbuffer << self.buffer.bits_to_go);
+            self.buffer.bitbuffer = lbitbuffer;
+            self.buffer.bits_to_go = lbits_to_go;
         }
 
-        // return number of bytes used
-        Ok(self.buffer.current)
+        Ok(lastpix)
     }
 
-    pub fn encode_byte(
-        &mut self,
-        input: &[i8],  /* input array			*/
-        nx: usize,     /* number of input pixels	*/
-        nblock: usize, /* coding block size		*/
-    ) -> Result<usize, EncodeError> {
-        if input.is_empty() || nblock == 0 {
-            return Err(EncodeError::ZeroSizeInput);
-        }
-
-        let mut nextpix: i8;
-        let mut pdiff: i8;
-
-        let mut v: i32;
-        let mut fs: i32;
-        let mut fsmask: i32;
-        let mut top: i32;
-
-        let mut lbitbuffer: i32;
-        let mut lbits_to_go: i32;
-
-        let mut psum: u8;
-        let mut pixelsum: f64;
-        let mut dpsum: f64;
-
-        /*
-         * From bsize derive:
-         * FSBITS = # bits required to store FS
-         * FSMAX = maximum value for FS
-         * BBITS = bits/pixel for direct coding
-         */
-
-        /* move out of switch block, to tweak performance */
-        let fsbits: i32 = 3;
-        let fsmax: i32 = 6;
-
-        let bbits: i32 = 1 << fsbits;
-
+    fn start_outputing_bits(&mut self) {
         /*
          * Set up buffer pointers
          */
@@ -494,169 +517,102 @@ impl<W: Write> RCEncoder<W> {
             bits_to_go: 8,
             bitbuffer: 0,
         };
+    }
 
-        /*
-         * array for differences mapped to non-negative values
-         */
-        let mut diff: Vec<u32> = vec![0; nblock];
-
-        /*
-         * Code in blocks of nblock pixels
-         */
-
-        // Initialize for bit output
-        self.buffer.bitbuffer = 0;
-        self.buffer.bits_to_go = 8;
-
-        /* write out first int value to the first 4 bytes of the buffer */
-        if self.output_nbits(input[0].into(), 8) == EOF {
-            (self.log_fn)("rice_encode: end of buffer");
-            return Err(EncodeError::EndOfBuffer);
+    fn done_outputing_bits(&mut self) -> Result<(), EncodeError> {
+        // Flush out the last bits
+        if self.buffer.bits_to_go < 8 {
+            self.putcbuf(self.buffer.bitbuffer << self.buffer.bits_to_go)?;
         }
+        self.buffer.bits_to_go = 8;
+        self.buffer.bitbuffer = 0;
+        self.flush_output()
+    }
 
-        let mut lastpix: i8 = input[0]; /* the first difference will always be zero */
-
-        let mut thisblock: usize = nblock;
-
-        for i in (0..nx).step_by(nblock) {
-            // for (i=0; i<nx; i += nblock) {
-            /* last block may be shorter */
-            if nx - i < nblock {
-                thisblock = nx - i;
-            }
-            /*
-             * Compute differences of adjacent pixels and map them to unsigned values.
-             * Note that this may overflow the integer variables -- that's
-             * OK, because we can recover when decompressing.  If we were
-             * compressing shorts or bytes, would want to do this arithmetic
-             * with short/byte working variables (though diff will still be
-             * passed as an int.)
-             *
-             * compute sum of mapped pixel values at same time
-             * use double precision for sum to allow 32-bit integer inputs
-             */
-            pixelsum = 0.0;
-            for j in 0..thisblock {
-                nextpix = input[i + j];
-                pdiff = nextpix.wrapping_sub(lastpix);
-                diff[j] = (if pdiff < 0 { !(pdiff << 1) } else { pdiff << 1 }) as u32; // ! is bitwise complement
-                pixelsum += diff[j] as f64;
-                lastpix = nextpix;
-            }
-
-            /*
-             * compute number of bits to split from sum
-             */
-            dpsum = (pixelsum - ((thisblock as f64) / 2.0) - 1.0) / (thisblock as f64);
-            if dpsum < 0.0 {
-                dpsum = 0.0;
-            }
-            psum = (dpsum as u8) >> 1;
+    /// Compress 32-bit unsigned pixels; the stream is identical to encoding
+    /// the same bits as `i32`
+    pub fn encode_unsigned(
+        &mut self,
+        input: &[u32], /* input array			*/
+        nx: usize,     /* number of input pixels	*/
+        nblock: usize, /* coding block size		*/
+    ) -> Result<usize, EncodeError> {
+        self.encode(input, nx, nblock)
+    }
 
-            fs = 0;
-            while psum > 0 {
-                psum >>= 1;
-                fs += 1;
-            }
+    /// Compress 16-bit signed pixels, as `fits_rcomp_short` does
+    pub fn encode_short(
+        &mut self,
+        input: &[i16], /* input array			*/
+        nx: usize,     /* number of input pixels	*/
+        nblock: usize, /* coding block size		*/
+    ) -> Result<usize, EncodeError> {
+        self.encode(input, nx, nblock)
+    }
 
-            /*
-             * write the codes
-             * fsbits ID bits used to indicate split level
-             */
-            if fs >= fsmax {
-                /* Special high entropy case when FS >= fsmax
-                 * Just write pixel difference values directly, no Rice coding at all.
-                 */
-                if self.output_nbits(fsmax + 1, fsbits) == EOF {
-                    (self.log_fn)("rice_encode: end of buffer");
-                    return Err(EncodeError::EndOfBuffer);
-                }
+    /// Compress 16-bit unsigned pixels
+    pub fn encode_ushort(
+        &mut self,
+        input: &[u16], /* input array			*/
+        nx: usize,     /* number of input pixels	*/
+        nblock: usize, /* coding block size		*/
+    ) -> Result<usize, EncodeError> {
+        self.encode(input, nx, nblock)
+    }
 
-                for &diff_item in diff.iter().take(thisblock) {
-                    if self.output_nbits(diff_item as i32, bbits) == EOF {
-                        (self.log_fn)("rice_encode: end of buffer");
-                        return Err(EncodeError::EndOfBuffer);
-                    }
-                }
-            } else if fs == 0 && pixelsum == 0.0 {
-                /*
-                 * special low entropy case when FS = 0 and pixelsum=0 (all
-                 * pixels in block are zero.)
-                 * Output a 0 and return
-                 */
-                if self.output_nbits(0, fsbits) == EOF {
-                    (self.log_fn)("rice_encode: end of buffer");
-                    return Err(EncodeError::EndOfBuffer);
-                }
-            } else {
-                /* normal case: not either very high or very low entropy */
-                if self.output_nbits(fs + 1, fsbits) == EOF {
-                    (self.log_fn)("rice_encode: end of buffer");
-                    return Err(EncodeError::EndOfBuffer);
-                }
-                fsmask = (1 << fs) - 1;
-                /*
-                 * local copies of bit buffer to improve optimization
-                 */
-                lbitbuffer = self.buffer.bitbuffer;
-                lbits_to_go = self.buffer.bits_to_go;
-                for &diff_item in diff.iter().take(thisblock) {
-                    v = diff_item as i32;
-                    top = v >> fs;
-                    /*
-                     * top is coded by top zeros + 1
-                     */
-                    if lbits_to_go > top {
-                        lbitbuffer = lbitbuffer.wrapping_shl((top + 1) as u32);
-                        lbitbuffer |= 1;
-                        lbits_to_go -= top + 1;
-                    } else {
-                        lbitbuffer <<= lbits_to_go;
-                        self.putcbuf(lbitbuffer & 0xff);
-
-                        top -= lbits_to_go;
-                        while top >= 8 {
-                            self.putcbuf(0);
-                            top -= 8;
-                        }
-
-                        lbitbuffer = 1;
-                        lbits_to_go = 7 - top;
-                    }
-                    /*
-                     * bottom FS bits are written without coding
-                     * code is output_nbits, moved into this routine to reduce overheads
-                     * This code potentially breaks if FS>24, so I am limiting
-                     * FS to 24 by choice of FSMAX above.
-                     */
-                    if fs > 0 {
-                        lbitbuffer <<= fs;
-                        lbitbuffer |= v & fsmask;
-                        lbits_to_go -= fs;
-                        while lbits_to_go <= 0 {
-                            self.putcbuf((lbitbuffer >> (-lbits_to_go)) & 0xff);
-                            lbits_to_go += 8;
-                        }
-                    }
-                }
+    /// Compress 64-bit signed pixels.
+    ///
+    /// This is an extension beyond the CFITSIO wire format: the FS field is
+    /// 6 bits wide and the high-entropy escape writes 64-bit raw values, so the
+    /// stream can only be read back by [`RCDecoder::decode_long`](crate::read::RCDecoder::decode_long).
+    pub fn encode_long(
+        &mut self,
+        input: &[i64], /* input array			*/
+        nx: usize,     /* number of input pixels	*/
+        nblock: usize, /* coding block size		*/
+    ) -> Result<usize, EncodeError> {
+        self.encode(input, nx, nblock)
+    }
 
-                self.buffer.bitbuffer = lbitbuffer;
-                self.buffer.bits_to_go = lbits_to_go;
-            }
-        }
+    /// Compress 64-bit unsigned pixels; see [`encode_long`](Self::encode_long)
+    pub fn encode_ulong(
+        &mut self,
+        input: &[u64], /* input array			*/
+        nx: usize,     /* number of input pixels	*/
+        nblock: usize, /* coding block size		*/
+    ) -> Result<usize, EncodeError> {
+        self.encode(input, nx, nblock)
+    }
 
-        // Flush out the last bits
-        if self.buffer.bits_to_go < 8 {
-            self.putcbuf(self.buffer.bitbuffer << self.buffer.bits_to_go);
-        }
+    /// Compress 8-bit signed pixels, as `fits_rcomp_byte` does
+    pub fn encode_byte(
+        &mut self,
+        input: &[i8],  /* input array			*/
+        nx: usize,     /* number of input pixels	*/
+        nblock: usize, /* coding block size		*/
+    ) -> Result<usize, EncodeError> {
+        self.encode(input, nx, nblock)
+    }
 
-        // return number of bytes used
-        Ok(self.buffer.current)
+    /// Compress 8-bit unsigned pixels
+    pub fn encode_ubyte(
+        &mut self,
+        input: &[u8],  /* input array			*/
+        nx: usize,     /* number of input pixels	*/
+        nblock: usize, /* coding block size		*/
+    ) -> Result<usize, EncodeError> {
+        self.encode(input, nx, nblock)
     }
 
     /*---------------------------------------------------------------------------*/
-    /// Output N bits (N must be <= 32)
-    fn output_nbits(&mut self, bits: i32, n: i32) -> i32 {
+    /// Output N bits (N must be <= 64)
+    fn output_nbits(&mut self, bits: i64, n: i32) -> Result<(), EncodeError> {
+        if n > 32 {
+            /* 64-bit values go out as two 32-bit halves, high half first */
+            self.output_nbits(bits >> 32, n - 32)?;
+            return self.output_nbits(bits, 32);
+        }
+
         /* local copies */
 
         let mut n = n;
@@ -672,7 +628,7 @@ impl<W: Write> RCEncoder<W> {
         /*
          * insert bits at end of bitbuffer
          */
-        let mut lbitbuffer: i32 = self.buffer.bitbuffer;
+        let mut lbitbuffer: i64 = self.buffer.bitbuffer;
         let mut lbits_to_go: i32 = self.buffer.bits_to_go;
         if lbits_to_go + n > 32 {
             /*
@@ -681,29 +637,285 @@ impl<W: Write> RCEncoder<W> {
              */
             lbitbuffer <<= lbits_to_go;
             /*	lbitbuffer |= (bits>>(n-lbits_to_go)) & ((1<<lbits_to_go)-1); */
-            lbitbuffer |= (bits >> (n - lbits_to_go)) & (MASK[lbits_to_go as usize] as i32);
-            self.putcbuf(lbitbuffer & 0xff);
+            lbitbuffer |= (bits >> (n - lbits_to_go)) & (MASK[lbits_to_go as usize] as i64);
+            self.putcbuf(lbitbuffer & 0xff)?;
             n -= lbits_to_go;
             lbits_to_go = 8;
         }
         lbitbuffer <<= n;
         /*    lbitbuffer |= ( bits & ((1<<n)-1) ); */
-        lbitbuffer |= bits & MASK[n as usize] as i32;
+        lbitbuffer |= bits & MASK[n as usize] as i64;
         lbits_to_go -= n;
         while lbits_to_go <= 0 {
             /*
              * bitbuffer full, put out top 8 bits
              */
-            self.putcbuf((lbitbuffer >> (-lbits_to_go)) & 0xff);
+            self.putcbuf((lbitbuffer >> (-lbits_to_go)) & 0xff)?;
             lbits_to_go += 8;
         }
         self.buffer.bitbuffer = lbitbuffer;
         self.buffer.bits_to_go = lbits_to_go;
-        0
+        Ok(())
     }
 
-    fn putcbuf(&mut self, c: i32) {
-        self.inner.write_all(&[c as u8]).unwrap();
+    /// Write bytes outside of the bit stream, such as a frame header
+    fn write_raw(&mut self, bytes: &[u8]) -> Result<(), EncodeError> {
+        /* encode_framed() replaces an EndOfBuffer with the size of the whole stream */
+        self.out.extend_from_slice(bytes);
+        self.flush_output()
+    }
+
+    #[inline]
+    fn putcbuf(&mut self, c: i64) -> Result<(), EncodeError> {
+        self.out.push(c as u8);
         self.buffer.current += 1;
+        if self.out.len() == OUTPUT_CHUNK {
+            self.flush_output()?;
+        }
+        Ok(())
+    }
+
+    /// Hand the staged output to the sink
+    fn flush_output(&mut self) -> Result<(), EncodeError> {
+        let result = self.inner.write_all(&self.out);
+        self.out.clear();
+        if let Err(err) = result {
+            if err.kind() == ErrorKind::WriteZero {
+                (self.log_fn)("rice_encode: end of buffer");
+                /* encode() replaces this with the size of the whole stream */
+                return Err(EncodeError::EndOfBuffer {
+                    needed: self.buffer.current,
+                });
+            }
+            (self.log_fn)("rice_encode: error writing output");
+            return Err(EncodeError::Io(err));
+        }
+        Ok(())
+    }
+}
+
+/// Rice encoder that accepts pixels incrementally.
+///
+/// Pixels can be pushed in pieces of any size, such as one detector row at a
+/// time; partial blocks are carried over between calls. The stream written by
+/// [`finish`](Self::finish) is byte-identical to a single
+/// [`RCEncoder::encode`] call over all the pixels.
+///
+/// If writing to the sink fails, part of a block may already have been
+/// coded, so every later call returns [`EncodeError::Poisoned`].
+pub struct RCStreamEncoder<W: Write, T: RiceSample> {
+    encoder: RCEncoder<W>,
+    nblock: usize,
+    lastpix: Option<T>, /* None until the first pixel is written */
+    pending: Vec<T>,    /* partial block carried over between pushes */
+    diff: Vec<u64>,
+    poisoned: bool,
+}
+
+impl<W: Write, T: RiceSample> RCStreamEncoder<W, T> {
+    pub fn new(inner: W, nblock: usize) -> Self {
+        RCStreamEncoder {
+            encoder: RCEncoder::new(inner),
+            nblock,
+            lastpix: None,
+            pending: Vec::with_capacity(nblock),
+            diff: vec![0; nblock],
+            poisoned: false,
+        }
+    }
+
+    pub fn set_log_fn(&mut self, log_fn: fn(&str)) {
+        self.encoder.set_log_fn(log_fn);
+    }
+
+    pub fn set_fs_strategy(&mut self, fs_strategy: FsStrategy) {
+        self.encoder.set_fs_strategy(fs_strategy);
+    }
+
+    /// Number of compressed bytes produced so far. Up to a chunk of them may
+    /// not have reached the sink until [`finish`](Self::finish).
+    pub fn bytes_written(&self) -> usize {
+        self.encoder.buffer.current
+    }
+
+    /// Add pixels to the stream, writing out every block that is complete.
+    pub fn push(&mut self, pixels: &[T]) -> Result<(), EncodeError> {
+        if self.poisoned {
+            return Err(EncodeError::Poisoned);
+        }
+        if self.nblock == 0 {
+            return Err(EncodeError::ZeroSizeInput);
+        }
+        if pixels.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.push_blocks(pixels);
+        self.poisoned = result.is_err();
+        result
+    }
+
+    fn push_blocks(&mut self, pixels: &[T]) -> Result<(), EncodeError> {
+        let mut lastpix = match self.lastpix {
+            Some(lastpix) => lastpix,
+            None => {
+                /* write out first value to the first bbits/8 bytes of the buffer */
+                self.encoder.start_outputing_bits();
+                self.encoder
+                    .output_nbits(pixels[0].to_bits() as i64, T::BBITS)?;
+                pixels[0]
+            }
+        };
+
+        let mut pixels = pixels;
+        if !self.pending.is_empty() {
+            /* top up the carried over block first */
+            let take = (self.nblock - self.pending.len()).min(pixels.len());
+            self.pending.extend_from_slice(&pixels[..take]);
+            pixels = &pixels[take..];
+            if self.pending.len() == self.nblock {
+                lastpix = self
+                    .encoder
+                    .encode_block(&self.pending, lastpix, &mut self.diff)?;
+                self.pending.clear();
+            }
+        }
+
+        let mut blocks = pixels.chunks_exact(self.nblock);
+        for block in blocks.by_ref() {
+            lastpix = self.encoder.encode_block(block, lastpix, &mut self.diff)?;
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+
+        self.lastpix = Some(lastpix);
+        Ok(())
+    }
+
+    /// Write out the last, possibly short, block and flush the remaining bits,
+    /// returning the total number of bytes written and the sink.
+    pub fn finish(mut self) -> Result<(usize, W), EncodeError> {
+        if self.poisoned {
+            return Err(EncodeError::Poisoned);
+        }
+        let lastpix = match self.lastpix {
+            Some(lastpix) => lastpix,
+            None => return Err(EncodeError::ZeroSizeInput),
+        };
+        if !self.pending.is_empty() {
+            self.encoder
+                .encode_block(&self.pending, lastpix, &mut self.diff)?;
+        }
+        self.encoder.done_outputing_bits()?;
+
+        Ok((self.encoder.buffer.current, self.encoder.inner))
+    }
+}
+
+/// Number of low bits to split off each mapped difference in a block of
+/// `thisblock` pixels whose mapped differences add up to `pixelsum`.
+///
+/// ricecomp.c computes this in floating point as
+/// `dpsum = (pixelsum - (thisblock/2) - 1)/thisblock`, clamped at zero, and
+/// `psum = ((unsigned int) dpsum) >> 1`. While `pixelsum` is below 2^53 the
+/// double division truncates to the same integer as exact division, so this
+/// gives the same `fs` as CFITSIO for all of its sample widths (blocks of up to
+/// 2^21 32-bit pixels); beyond that the double only approximated the value
+/// computed here.
+pub(crate) fn split_fs(pixelsum: u128, thisblock: usize) -> i32 {
+    /* thisblock / 2 is integer division, as in ricecomp.c */
+    let dpsum = pixelsum.saturating_sub((thisblock / 2) as u128 + 1) / thisblock as u128;
+    let psum = dpsum >> 1;
+
+    /* fs is the number of significant bits in psum */
+    (u128::BITS - psum.leading_zeros()) as i32
+}
+
+/// Split parameter that codes a block of mapped differences in the fewest
+/// bits. Returns `fsmax` if coding the pixels directly is shortest, and 0 for
+/// an all-zero block.
+pub(crate) fn optimal_fs(diff: &[u64], fsmax: i32, bbits: i32) -> i32 {
+    if diff.iter().all(|&d| d == 0) {
+        /* only the block header is written */
+        return 0;
+    }
+
+    let n = diff.len() as u128;
+    let mut best = 0;
+    let mut best_bits = u128::MAX;
+    for fs in 0..fsmax {
+        let bits = rice_bits(diff, fs);
+        if bits < best_bits {
+            best = fs;
+            best_bits = bits;
+        }
+        if bits == n * (fs as u128 + 1) {
+            /* every top is zero, larger fs only adds low bits */
+            break;
+        }
     }
+
+    if n * (bbits as u128) < best_bits {
+        return fsmax;
+    }
+    best
+}
+
+/// How a block is coded with split parameter `fs`, and its length in bits
+/// including the block header
+fn block_bits<T: RiceSample>(diff: &[u64], fs: i32, pixelsum: u128) -> (BlockKind, u128) {
+    let header = T::FSBITS as u128;
+    if fs >= T::FSMAX {
+        (
+            BlockKind::Raw,
+            header + diff.len() as u128 * T::BBITS as u128,
+        )
+    } else if fs == 0 && pixelsum == 0 {
+        (BlockKind::Zero, header)
+    } else {
+        (BlockKind::Rice, header + rice_bits(diff, fs))
+    }
+}
+
+/// Bits taken by the Rice codes of a block, excluding its header: each pixel
+/// costs its top bits in unary, a one-bit and `fs` low bits
+fn rice_bits(diff: &[u64], fs: i32) -> u128 {
+    let tops: u128 = diff.iter().map(|&d| (d >> fs) as u128).sum();
+    tops + diff.len() as u128 * (fs as u128 + 1)
+}
+
+/// Compress `nx` pixels of `input` into the start of `output`, returning the
+/// number of bytes used.
+///
+/// Like the `clen` argument of `fits_rcomp`, `output.len()` is the capacity:
+/// if the stream does not fit, [`EncodeError::EndOfBuffer`] reports how many
+/// bytes it needs. [`max_compressed_size`] gives a capacity that always fits.
+pub fn encode_into<T: RiceSample>(
+    input: &[T],       /* input array			*/
+    nx: usize,         /* number of input pixels	*/
+    nblock: usize,     /* coding block size		*/
+    output: &mut [u8], /* output buffer		*/
+) -> Result<usize, EncodeError> {
+    RCEncoder::new(output).encode(input, nx, nblock)
+}
+
+/// Upper bound on the size in bytes of the stream for `nx` pixels of the
+/// given width coded in blocks of `nblock` pixels.
+///
+/// Rice coded blocks can exceed the directly coded size by a little for
+/// 8 and 16-bit samples, so the bound is slightly above `nx * bytepix`.
+pub fn max_compressed_size(nx: usize, nblock: usize, width: SampleWidth) -> usize {
+    let nblock = nblock.max(1);
+    let (fsbits, fsmax, bbits) = (width.fsbits(), width.fsmax(), width.bbits());
+
+    /*
+     * FS is chosen so that the sum of mapped differences in a block of n
+     * pixels is below n * 2^(fs+1) + n/2 + 1, which bounds the unary part of
+     * the codes by 2.5n + 1 bits. With fs < fsmax that gives at most
+     * n * (fsmax + 3) + 1 bits, against n * bbits for a directly coded block.
+     */
+    let per_pixel = bbits.max(fsmax + 3) as usize;
+    let per_block = (fsbits + 1) as usize;
+    let bits = nx * per_pixel + nx.div_ceil(nblock) * per_block;
+
+    width.bytepix() + bits.div_ceil(8)
 }